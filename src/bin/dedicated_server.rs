@@ -0,0 +1,105 @@
+//! Runs the server without a window, accepting clients over UDP.
+//!
+//! `dedicated_server [--port 7777] [--tick-rate 50]`
+
+use netcode_example::clock::{Clock, RealClock};
+use netcode_example::server::Server;
+use netcode_example::udp::UdpListener;
+use std::ops::RangeInclusive;
+use std::thread;
+use std::time::Duration;
+
+/// Tick rates the server can keep up with and still sleep between polls.
+const TICK_RATES: RangeInclusive<f32> = 1.0..=1000.0;
+
+struct Options {
+    port: u16,
+    tick_rate: f32,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        port: 7777,
+        tick_rate: 50.0,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+        match arg.as_str() {
+            "--port" => {
+                options.port = value
+                    .parse()
+                    .map_err(|_| format!("invalid port: {}", value))?;
+            }
+            "--tick-rate" => {
+                options.tick_rate = value
+                    .parse()
+                    .ok()
+                    .filter(|rate| TICK_RATES.contains(rate))
+                    .ok_or_else(|| {
+                        format!(
+                            "invalid tick rate: {} (expected {} to {})",
+                            value,
+                            TICK_RATES.start(),
+                            TICK_RATES.end()
+                        )
+                    })?;
+            }
+            _ => return Err(format!("unknown argument: {}", arg)),
+        }
+    }
+
+    Ok(options)
+}
+
+fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("usage: dedicated_server [--port 7777] [--tick-rate 50]");
+            std::process::exit(2);
+        }
+    };
+
+    let mut listener =
+        UdpListener::bind(("0.0.0.0", options.port)).expect("failed to bind UDP socket");
+    println!(
+        "Listening on {} at {} ticks per second",
+        listener.local_addr().unwrap(),
+        options.tick_rate
+    );
+
+    let clock = RealClock::new();
+    let mut server = Server::new();
+    server.update_interval = 1.0 / options.tick_rate;
+
+    let mut last_time = clock.now();
+    loop {
+        for peer in listener.poll() {
+            println!("Client connecting from {}", peer.addr());
+            server.connect(Box::new(peer));
+        }
+
+        let now = clock.now();
+        let second = server.time as u64;
+        server.update((now - last_time) as f32);
+        last_time = now;
+
+        // once a second, every tick would flood the terminal
+        if server.time as u64 != second {
+            println!(
+                "Tick {}: {} clients, {} entities",
+                server.tick,
+                server.connections.len(),
+                server.entities.len()
+            );
+        }
+
+        // poll a few times per tick so inputs are picked up promptly
+        thread::sleep(Duration::from_secs_f32(server.update_interval / 4.0));
+    }
+}
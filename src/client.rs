@@ -1,186 +1,494 @@
-use crate::server::Server;
-use crate::{get_time_ms, Entity, LagNetwork, Message, MovementInput};
-use macroquad::time::get_time;
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::hash::Hash;
-use std::rc::{Rc, Weak};
-
-pub(crate) struct Client {
-    pub server: Weak<RefCell<Server>>, // Weak reference to avoid circular dependency
+use crate::clock::Clock;
+use crate::protocol;
+use crate::reliability::Endpoint;
+use crate::rtt::RttEstimator;
+use crate::server::{CONNECTION_TIMEOUT, SNAPSHOT_HISTORY};
+use crate::transport::Transport;
+use crate::{world_state, Entity, InputBufferStatus, Message, MovementInput};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::rc::Rc;
+
+/// How far each snapshot moves the server clock estimate towards what it implies.
+const CLOCK_OFFSET_SMOOTHING: f64 = 0.1;
+
+/// Seconds the server clock estimate may be off before it is reset instead of smoothed.
+const CLOCK_SNAP_THRESHOLD: f64 = 1.0;
+
+/// Ticks an input is stamped ahead of when it should reach the server, to absorb jitter,
+/// until the server says how deep it wants its input buffer.
+const INPUT_LEAD_TICKS: u32 = 2;
+
+/// How much faster or slower than the server the client ticks while it fills up or drains
+/// the server's input buffer.
+const INPUT_RATE_ADJUSTMENT: f32 = 0.05;
+
+/// Ticks the client may drift from where it should be before it jumps back into place.
+const TICK_RESYNC_THRESHOLD: u32 = 4;
+
+/// Disconnect messages sent when leaving, as there is nobody left to resend a lost one.
+const DISCONNECT_REDUNDANCY: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientState {
+    /// Sending connect requests until the server challenges us.
+    Connecting,
+    /// Echoing the server's salt until it accepts or denies us.
+    Challenged {
+        salt: u32,
+    },
+    Connected,
+    Denied,
+    TimedOut,
+    Disconnected,
+}
+
+/// How entities other than our own are moved between snapshots.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RemoteEntityMode {
+    /// Jump to each snapshot as it arrives.
+    Snap,
+    /// Show them a little in the past, between the two snapshots around that time.
+    Interpolate,
+    /// Like `Interpolate`, but along a cubic curve whose speed carries over from one pair of
+    /// snapshots to the next instead of jumping at every snapshot.
+    InterpolateCubic,
+    /// Carry on from the newest snapshot at the speed they were last seen moving, for at
+    /// most `max_time` seconds, and fade into each new snapshot over `blend_time` seconds.
+    Extrapolate { max_time: f32, blend_time: f32 },
+}
+
+impl RemoteEntityMode {
+    /// Cycles to the next mode.
+    pub fn next(&self) -> RemoteEntityMode {
+        match self {
+            RemoteEntityMode::Snap => RemoteEntityMode::Interpolate,
+            RemoteEntityMode::Interpolate => RemoteEntityMode::InterpolateCubic,
+            RemoteEntityMode::InterpolateCubic => RemoteEntityMode::Extrapolate {
+                max_time: 0.25,
+                blend_time: 0.1,
+            },
+            RemoteEntityMode::Extrapolate { .. } => RemoteEntityMode::Snap,
+        }
+    }
+}
+
+impl fmt::Display for RemoteEntityMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemoteEntityMode::Snap => write!(f, "Snap"),
+            RemoteEntityMode::Interpolate => write!(f, "Interpolate"),
+            RemoteEntityMode::InterpolateCubic => write!(f, "Interpolate (cubic)"),
+            RemoteEntityMode::Extrapolate { max_time, .. } => {
+                write!(f, "Extrapolate up to {:.0}ms", max_time * 1000.0)
+            }
+        }
+    }
+}
+
+/// Difference between where an extrapolated entity was drawn and where the snapshot that
+/// just landed puts it, fading out from `since`, in local time.
+struct Blend {
+    snapshot_time_ms: u128,
+    error: f32,
+    since: f64,
+}
+
+pub struct Client {
+    pub state: ClientState,
+    pub clock: Rc<dyn Clock>,
     pub update_interval: f32,
     pub time_since_last_update: f32,
+    /// Ticks run per server tick. A little off 1 while the server's input buffer for us is
+    /// off its target depth.
+    pub input_rate: f32,
+    /// Latest state of the server's buffer of our inputs.
+    pub input_buffer: Option<InputBufferStatus>,
     pub key_left: bool,
     pub key_right: bool,
-    pub last_time: f64,
+    /// Server tick the next input is for. The client runs ahead of the server by about a
+    /// round trip, so its inputs arrive just before they are needed.
+    pub tick: u32,
     pub input_sequence_number: u32,
-    pub entity_id: u32,
-    pub network: LagNetwork,
+    pub entity_id: u32, // 0 until the server has assigned one
+    pub endpoint: Endpoint,
+    /// Local time the last message from the server arrived.
+    last_heard: f64,
     pub entities: HashMap<u32, Entity>,
     pub client_side_prediction: bool,
     pub server_reconciliation: bool,
     pub pending_inputs: Vec<MovementInput>,
-    pub latency_to_server: f32,
-    pub entity_interpolation: bool,
+    /// Fade reconciliation corrections out on screen instead of popping to them.
+    pub smooth_corrections: bool,
+    /// Time constant, in seconds, of the fade.
+    pub correction_time: f32,
+    /// Corrections bigger than this are snapped to straight away.
+    pub correction_snap_distance: f32,
+    /// Where our entity is drawn minus where it is simulated. Fades to zero.
+    correction_error: f32,
+    /// How many of the newest pending inputs are repeated in every packet to the server.
+    pub input_redundancy: usize,
+    pub last_world_state_sequence: Option<u32>,
+    /// Full world state of recently received snapshots, oldest first, for resolving deltas.
+    pub received_snapshots: VecDeque<(u32, Vec<world_state>)>,
+    /// Fractional bits the server quantizes positions to, so prediction can do the same.
+    position_bits: Option<u8>,
+    pub remote_entity_mode: RemoteEntityMode,
+    blends: HashMap<u32, Blend>,
+    pub rtt: RttEstimator,
+    /// Estimated server time minus local time, in seconds, once a snapshot has arrived.
+    pub server_time_offset: Option<f64>,
+    /// Smoothed server time between the snapshots we get, in seconds. The server adapts it to
+    /// the link, so it is measured rather than known.
+    pub snapshot_interval: Option<f32>,
+    last_snapshot_time_ms: Option<u32>,
+    /// Local time we first answered the server's challenge, to time the handshake by.
+    challenge_answered_at: Option<f64>,
 }
 
 impl Client {
-    pub fn new(server: Weak<RefCell<Server>>, update_interval: f32) -> Self {
-        // Get the current time as SystemTime
-
-        // Convert the duration to seconds as a f64
-        let last_time = get_time();
-
-        // Set the entity id to length of the clients
-        let entity_id = server.upgrade().unwrap().borrow().clients.len() as u32 + 1;
+    /// Creates a client that starts connecting to the server at the other end of `transport`.
+    /// It sends every `update_interval` seconds until the server tells it its tick length.
+    pub fn new(update_interval: f32, clock: Rc<dyn Clock>, transport: Box<dyn Transport>) -> Self {
+        let now = clock.now();
 
         Client {
-            server,
+            state: ClientState::Connecting,
+            clock,
+            endpoint: Endpoint::new(transport),
+            last_heard: now,
             update_interval,
             time_since_last_update: 0.0,
+            input_rate: 1.0,
+            input_buffer: None,
             key_left: false,
             key_right: false,
-            last_time, // Set the current epoch time as last_time
-            input_sequence_number: 0,
-            entity_id: entity_id,
-            network: LagNetwork { messages: vec![] },
+            tick: 0,
+            input_sequence_number: 1, // 0 is what the server reports before any input
+            entity_id: 0,
             entities: HashMap::new(),
             client_side_prediction: false,
             server_reconciliation: false,
             pending_inputs: Vec::new(),
-            latency_to_server: 250.0,
-            entity_interpolation: false,
+            smooth_corrections: false,
+            correction_time: 0.1,
+            correction_snap_distance: 50.0,
+            correction_error: 0.0,
+            input_redundancy: 8,
+            last_world_state_sequence: None,
+            received_snapshots: VecDeque::new(),
+            position_bits: None,
+            remote_entity_mode: RemoteEntityMode::Snap,
+            blends: HashMap::new(),
+            rtt: RttEstimator::new(),
+            server_time_offset: None,
+            snapshot_interval: None,
+            last_snapshot_time_ms: None,
+            challenge_answered_at: None,
         }
     }
 
-    pub fn get_server(&self) -> Option<Rc<RefCell<Server>>> {
-        self.server.upgrade()
-    }
-
-    pub fn process_input(&mut self) -> Option<Message> {
-        let seconds = get_time();
-        let mut delta_seconds = ((seconds - self.last_time) / 1000.0) as f32;
-
-        self.last_time = seconds;
-
-        if self.key_left {
-            // println!("Client moving left! Delta time: {}", delta_seconds);
-            delta_seconds = -delta_seconds;
+    /// Makes the input for the current tick. One is made every tick, standing still included,
+    /// so the server simulates exactly the ticks the client predicted.
+    pub fn process_input(&mut self) {
+        let direction = if self.key_left {
+            -1
         } else if self.key_right {
-            // println!("Client moving right! Delta time: {}", delta_seconds);
+            1
         } else {
-            return None;
-        }
+            0
+        };
 
         // Create a movement input
         let movement_input = MovementInput {
-            press_time: delta_seconds as f32,
+            tick: self.tick,
+            direction,
             entity_id: self.entity_id,
             input_sequence_number: self.input_sequence_number,
         };
 
         // Increment the input sequence number
         self.input_sequence_number += 1;
+        self.tick += 1;
 
-        if (self.client_side_prediction) {
+        if self.client_side_prediction {
             // Apply the movement input to the entity immediately for client-side prediction
             if let Some(entity) = self.entities.get_mut(&self.entity_id) {
-                entity.applyInput(movement_input.clone());
+                predict(
+                    entity,
+                    movement_input.clone(),
+                    self.update_interval,
+                    self.position_bits,
+                );
             }
         }
 
-        // add to pending inputs
-        self.pending_inputs.push(movement_input.clone());
-
-        // Return the movement input as a message
-        Some(Message::Movement(movement_input))
+        // add to pending inputs, they are sent from there until the server confirms them
+        self.pending_inputs.push(movement_input);
     }
 
     pub fn proccessServerMessages(&mut self) {
         // Process messages from the server
         // println!("Processing server message...");
 
-        while true {
-            if let Some(msg) = self.network.receive() {
-                match msg {
-                    Message::WorldState(world_state) => {
-                        for world_state in world_state.world_state {
-                            // if this is first time we see this entity, add it to the list
-                            if !self.entities.contains_key(&world_state.entity_id) {
-                                let entity = Entity::new(world_state.entity_id);
-                                self.entities.insert(world_state.entity_id, entity);
+        while let Some(msg) = self.endpoint.receive() {
+            self.last_heard = self.clock.now();
+
+            match msg {
+                Message::WorldState(world_state) => {
+                    // snapshots without entities don't say how positions are quantized
+                    let position_bits =
+                        (!world_state.world_state.is_empty()).then_some(world_state.position_bits);
+
+                    // rebuild the complete state from the baseline the delta is against
+                    let full_state = match world_state.baseline {
+                        None => world_state.world_state,
+                        Some(baseline) => {
+                            match self.received_snapshots.iter().find(|(s, _)| *s == baseline) {
+                                Some((_, baseline)) => world_state.apply_to(baseline),
+                                // too old to resolve, and the server thinks we have it
+                                None => {
+                                    self.endpoint.send_unreliable(Message::SnapshotNack {
+                                        sequence: world_state.sequence,
+                                    });
+                                    continue;
+                                }
                             }
+                        }
+                    };
+
+                    // kept even when overtaken, the packet it came in acks it as a baseline
+                    self.received_snapshots
+                        .push_back((world_state.sequence, full_state.clone()));
+                    if self.received_snapshots.len() > SNAPSHOT_HISTORY {
+                        self.received_snapshots.pop_front();
+                    }
+
+                    // ignore overtaken snapshots, a newer one was already applied
+                    if self
+                        .last_world_state_sequence
+                        .is_some_and(|last| world_state.sequence <= last)
+                    {
+                        continue;
+                    }
+                    self.last_world_state_sequence = Some(world_state.sequence);
+                    if let Some(position_bits) = position_bits {
+                        self.position_bits = position_bits;
+                    }
+
+                    let target_tick = world_state.tick + self.lead_ticks();
+                    if self.tick.abs_diff(target_tick) > TICK_RESYNC_THRESHOLD {
+                        self.tick = target_tick;
+                    }
+
+                    // the snapshot was stamped about half a round trip ago
+                    let one_way = self.rtt.smoothed.unwrap_or(0.0) as f64 / 2.0;
+                    let sample = world_state.time_ms as f64 / 1000.0 + one_way - self.clock.now();
+                    self.server_time_offset = Some(match self.server_time_offset {
+                        Some(offset) if (sample - offset).abs() < CLOCK_SNAP_THRESHOLD => {
+                            offset + CLOCK_OFFSET_SMOOTHING * (sample - offset)
+                        }
+                        // the first snapshot, or e.g. a restarted server
+                        _ => sample,
+                    });
+                    if let Some(last) = self.last_snapshot_time_ms {
+                        let gap = world_state.time_ms.saturating_sub(last) as f32 / 1000.0;
+                        self.snapshot_interval = Some(match self.snapshot_interval {
+                            Some(interval) => interval + 0.1 * (gap - interval),
+                            None => gap,
+                        });
+                    }
+                    self.last_snapshot_time_ms = Some(world_state.time_ms);
+                    let snapshot_time_ms = world_state.time_ms as u128;
 
-                            if let Some(entity) = self.entities.get_mut(&world_state.entity_id) {
-                                if world_state.entity_id == self.entity_id {
+                    // entities missing from the snapshot have left
+                    let present: Vec<u32> = full_state.iter().map(|s| s.entity_id).collect();
+                    self.entities.retain(|id, _| present.contains(id));
+                    self.blends.retain(|id, _| present.contains(id));
+
+                    for world_state in full_state {
+                        // if this is first time we see this entity, or it just came back into
+                        // relevancy, add it to the list where the server has it
+                        let entity =
+                            self.entities
+                                .entry(world_state.entity_id)
+                                .or_insert_with(|| {
+                                    let mut entity = Entity::new(world_state.entity_id);
                                     entity.x = world_state.position;
+                                    entity
+                                });
 
-                                    if self.server_reconciliation {
-                                        // re-apply the pending inputs
-                                        let mut j = 0;
-                                        while (j < self.pending_inputs.len()) {
-                                            let input = self.pending_inputs[j].clone();
-                                            if input.input_sequence_number
-                                                <= world_state.last_processed_input as u32
-                                            {
-                                                self.pending_inputs.remove(j);
-                                            } else {
-                                                // apply the input to the entity
-                                                entity.applyInput(input.clone());
-                                                j += 1;
-                                            }
-                                        }
-                                    } else {
-                                        self.pending_inputs.clear();
-                                    }
-                                } else {
-                                    if !self.entity_interpolation {
-                                        entity.x = world_state.position;
+                        if world_state.entity_id == self.entity_id {
+                            let drawn_at = entity.x + self.correction_error;
+                            entity.x = world_state.position;
+
+                            if self.server_reconciliation {
+                                // re-apply the pending inputs
+                                let mut j = 0;
+                                while j < self.pending_inputs.len() {
+                                    let input = self.pending_inputs[j].clone();
+                                    if input.input_sequence_number
+                                        <= world_state.last_processed_input as u32
+                                    {
+                                        self.pending_inputs.remove(j);
                                     } else {
-                                        let in_ms: u128 = get_time_ms();
-                                        entity.position_buffer.push((in_ms, world_state.position));
+                                        // apply the input to the entity
+                                        predict(
+                                            entity,
+                                            input,
+                                            self.update_interval,
+                                            self.position_bits,
+                                        );
+                                        j += 1;
                                     }
                                 }
+
+                                // keep drawing it where it was, and fade the difference out
+                                let error = drawn_at - entity.x;
+                                self.correction_error = if self.smooth_corrections
+                                    && error.abs() <= self.correction_snap_distance
+                                {
+                                    error
+                                } else {
+                                    0.0
+                                };
+                            } else {
+                                // not re-applied, but still repeated until the server has them
+                                self.pending_inputs.retain(|input| {
+                                    input.input_sequence_number
+                                        > world_state.last_processed_input as u32
+                                });
                             }
+                        } else if self.remote_entity_mode == RemoteEntityMode::Snap {
+                            entity.x = world_state.position;
+                        } else {
+                            entity
+                                .position_buffer
+                                .push((snapshot_time_ms, world_state.position));
                         }
                     }
-                    Message::Movement(movement_input) => {
-                        // clients wont get this
+                }
+                Message::Challenge { salt } => {
+                    if self.state == ClientState::Connecting {
+                        self.state = ClientState::Challenged { salt };
                     }
                 }
-            } else {
-                // No more messages to process
-                break;
+                Message::Accepted {
+                    entity_id,
+                    tick,
+                    tick_interval,
+                } => {
+                    self.state = ClientState::Connected;
+                    self.entity_id = entity_id;
+                    self.update_interval = tick_interval;
+                    // the handshake is the first round trip we can time, lead by it until
+                    // pings take over, or the first inputs arrive after their tick
+                    if let Some(answered_at) = self.challenge_answered_at.take() {
+                        self.rtt.add_sample((self.clock.now() - answered_at) as f32);
+                    }
+                    self.tick = tick + self.lead_ticks();
+                }
+                Message::Denied => {
+                    println!("The server denied the connection");
+                    self.state = ClientState::Denied;
+                }
+                Message::Ping { id } => {
+                    // right away, any wait would count towards the server's round trip
+                    self.endpoint.send_unreliable(Message::Pong { id });
+                    self.endpoint.flush();
+                }
+                Message::Pong { id } => {
+                    self.rtt.on_pong(id, self.clock.now());
+                }
+                Message::InputBuffer(status) => {
+                    // speed up while the buffer runs low or inputs miss their tick, slow
+                    // down while it fills up
+                    let target = status.target_depth as i32;
+                    let late = self
+                        .input_buffer
+                        .is_some_and(|last| status.late > last.late);
+                    self.input_rate = if late || status.depth < target {
+                        1.0 + INPUT_RATE_ADJUSTMENT
+                    } else if status.depth > target + 1 {
+                        1.0 - INPUT_RATE_ADJUSTMENT
+                    } else {
+                        1.0
+                    };
+                    self.input_buffer = Some(status);
+                }
+                Message::KeepAlive => {}
+                Message::Movement(_)
+                | Message::ConnectRequest
+                | Message::ChallengeResponse { .. }
+                | Message::Disconnect
+                | Message::SnapshotNack { .. } => {
+                    // clients wont get this
+                }
             }
         }
     }
 
-    pub fn interpolateEntities(&mut self, server_update_interval: f32) {
-        let in_ms = get_time_ms();
+    /// Where `entity` should be drawn. That is its simulated position, except for our own
+    /// entity while a correction is fading out.
+    pub fn render_x(&self, entity: &Entity) -> f32 {
+        if entity.entity_id == self.entity_id {
+            entity.x + self.correction_error
+        } else {
+            entity.x
+        }
+    }
+
+    /// Current time on the server's clock, as far as the client can tell.
+    pub fn server_time(&self) -> Option<f64> {
+        self.server_time_offset
+            .map(|offset| self.clock.now() + offset)
+    }
 
-        let render_timestamp = in_ms - (1000.0 * server_update_interval as f32).floor() as u128;
+    /// Moves the other entities to where they were a little in the past, linearly between
+    /// snapshots or along a Hermite curve if `cubic`.
+    pub fn interpolateEntities(&mut self, cubic: bool) {
+        let Some(server_time) = self.server_time() else {
+            return;
+        };
+
+        // render in server time, one snapshot interval behind the newest snapshot that can
+        // have arrived by now
+        let one_way = self.rtt.smoothed.unwrap_or(0.0) as f64 / 2.0;
+        let delay = self
+            .update_interval
+            .max(self.snapshot_interval.unwrap_or(0.0)) as f64;
+        let render_timestamp = ((server_time - one_way - delay) * 1000.0).max(0.0) as u128;
 
         for (id, entity) in &mut self.entities {
             if id == &self.entity_id {
                 continue;
             }
 
-            // Find the two authoritative positions surrounding the rendering timestamp.
-            let mut buffer = entity.position_buffer.clone();
-
-            while buffer.len() >= 2 && buffer[1].0 <= render_timestamp {
-                buffer.remove(0);
-            }
+            // Find the two authoritative positions surrounding the rendering timestamp, and
+            // drop the older ones except the one just before, which the curve bends from.
+            let buffer = &mut entity.position_buffer;
+            let from = buffer
+                .partition_point(|(time, _)| *time <= render_timestamp)
+                .saturating_sub(1);
+            buffer.drain(..from.saturating_sub(1));
+            let (before, buffer) = match from {
+                0 => (None, &buffer[..]),
+                _ => (Some(buffer[0]), &buffer[1..]),
+            };
 
-            if (buffer.len() >= 2
+            if buffer.len() >= 2
                 && buffer[0].0 <= render_timestamp
-                && render_timestamp <= buffer[1].0)
+                && render_timestamp <= buffer[1].0
             {
                 let t =
                     (render_timestamp - buffer[0].0) as f32 / (buffer[1].0 - buffer[0].0) as f32;
-                let interpolated_position = buffer[0].1 + t * (buffer[1].1 - buffer[0].1);
+                let interpolated_position = if cubic {
+                    hermite(before, buffer[0], buffer[1], t)
+                } else {
+                    buffer[0].1 + t * (buffer[1].1 - buffer[0].1)
+                };
                 entity.x = interpolated_position;
-            } else if (buffer.len() == 1 && buffer[0].0 <= render_timestamp) {
+            } else if buffer.len() == 1 && buffer[0].0 <= render_timestamp {
                 entity.x = buffer[0].1;
             } else {
                 // println!("No interpolation needed for entity {}", id);
@@ -188,25 +496,197 @@ impl Client {
         }
     }
 
-    pub fn update(&mut self, delta_time: f32, server_update_interval: f32) -> Option<Message> {
+    /// Moves the other entities to where they would be now on the server, if they kept going
+    /// as in the newest snapshots.
+    pub fn extrapolateEntities(&mut self, max_time: f32, blend_time: f32) {
+        let Some(server_time) = self.server_time() else {
+            return;
+        };
+        // the server time estimate is nudged by every snapshot, the fade needs steady time
+        let now = self.clock.now();
+
+        for (id, entity) in &mut self.entities {
+            if id == &self.entity_id {
+                continue;
+            }
+
+            // only the two newest snapshots are needed
+            let buffer = &mut entity.position_buffer;
+            buffer.drain(..buffer.len().saturating_sub(2));
+            let Some(&(newest_ms, newest)) = buffer.last() else {
+                continue;
+            };
+            // per millisecond, from the two newest snapshots
+            let velocity = match buffer.len().checked_sub(2).map(|i| buffer[i]) {
+                Some((previous_ms, previous)) if previous_ms < newest_ms => {
+                    (newest - previous) / (newest_ms - previous_ms) as f32
+                }
+                _ => 0.0,
+            };
+
+            // past the limit it is guesswork, so the entity stops and waits
+            let ahead =
+                (server_time * 1000.0 - newest_ms as f64).clamp(0.0, max_time as f64 * 1000.0);
+            let extrapolated = newest + velocity * ahead as f32;
+
+            let blend = self.blends.entry(*id).or_insert(Blend {
+                snapshot_time_ms: newest_ms,
+                error: 0.0,
+                since: now,
+            });
+            if blend.snapshot_time_ms != newest_ms {
+                // a new snapshot landed, fade into it from where the entity is drawn
+                *blend = Blend {
+                    snapshot_time_ms: newest_ms,
+                    error: entity.x - extrapolated,
+                    since: now,
+                };
+            }
+            let elapsed = (now - blend.since).max(0.0) as f32;
+            let fade = (-elapsed / blend_time.max(f32::EPSILON)).exp();
+            entity.x = extrapolated + blend.error * fade;
+        }
+    }
+
+    pub fn update(&mut self, delta_time: f32) {
         // Accumulate time for the client
         self.time_since_last_update += delta_time;
 
-        // Update the client only if the update interval has passed
-        if self.time_since_last_update >= self.update_interval {
-            self.time_since_last_update -= self.update_interval; // Reset time
-                                                                 // println!("Client updated!");
-                                                                 // println!("Client updated!");
-                                                                 // Perform client update tasks, such as processing input
+        // every frame, so the fade is as smooth as the frame rate allows
+        self.correction_error *= (-delta_time / self.correction_time.max(f32::EPSILON)).exp();
+
+        // every frame too, so pings are answered without waiting for a tick
+        if self.is_active() {
             self.proccessServerMessages();
+        }
 
-            if self.entity_interpolation {
-                self.interpolateEntities(server_update_interval);
+        // fixed ticks, catching up if more than one has passed. They are simulated as a whole
+        // tick each however fast they are run
+        let interval = self.update_interval / self.input_rate;
+        while self.time_since_last_update >= interval {
+            self.time_since_last_update -= interval; // Reset time
+            self.run_tick();
+        }
+    }
+
+    fn run_tick(&mut self) {
+        if !self.is_active() {
+            return;
+        }
+
+        if self.clock.now() - self.last_heard > CONNECTION_TIMEOUT {
+            println!("Connection to the server timed out");
+            self.state = ClientState::TimedOut;
+        }
+
+        match self.remote_entity_mode {
+            RemoteEntityMode::Snap => {}
+            RemoteEntityMode::Interpolate => self.interpolateEntities(false),
+            RemoteEntityMode::InterpolateCubic => self.interpolateEntities(true),
+            RemoteEntityMode::Extrapolate {
+                max_time,
+                blend_time,
+            } => self.extrapolateEntities(max_time, blend_time),
+        }
+
+        match self.state {
+            // keep asking until the server has let us in
+            ClientState::Connecting => {
+                self.endpoint.send_unreliable(Message::ConnectRequest);
+            }
+            ClientState::Challenged { salt } => {
+                let now = self.clock.now();
+                self.challenge_answered_at.get_or_insert(now);
+                self.endpoint
+                    .send_unreliable(Message::ChallengeResponse { salt });
             }
+            ClientState::Connected => self.send_inputs(),
+            ClientState::Denied | ClientState::TimedOut | ClientState::Disconnected => return,
+        }
+        // sent even without input, it carries the acks for the server's snapshots
+        self.endpoint.flush();
+    }
 
-            self.process_input()
-        } else {
-            None
+    /// Ticks to stamp inputs ahead of the server's latest known tick: about a round trip, plus
+    /// what the server buffers.
+    fn lead_ticks(&self) -> u32 {
+        let buffered = self
+            .input_buffer
+            .map_or(INPUT_LEAD_TICKS, |status| status.target_depth);
+        (self.rtt.smoothed.unwrap_or(0.0) / self.update_interval).ceil() as u32 + buffered
+    }
+
+    /// Whether the client is connected or still trying to be.
+    pub fn is_active(&self) -> bool {
+        matches!(
+            self.state,
+            ClientState::Connecting | ClientState::Challenged { .. } | ClientState::Connected
+        )
+    }
+
+    /// Leaves the server, which removes our entity straight away.
+    pub fn disconnect(&mut self) {
+        if !self.is_active() {
+            return;
         }
+        for _ in 0..DISCONNECT_REDUNDANCY {
+            self.endpoint.send_unreliable(Message::Disconnect);
+            self.endpoint.flush();
+        }
+        self.state = ClientState::Disconnected;
+    }
+
+    fn send_inputs(&mut self) {
+        self.process_input();
+
+        // repeat the newest unconfirmed inputs, so a lost packet doesn't lose them
+        let start = self
+            .pending_inputs
+            .len()
+            .saturating_sub(self.input_redundancy);
+        for input in &self.pending_inputs[start..] {
+            self.endpoint
+                .send_unreliable(Message::Movement(input.clone()));
+        }
+        if start == self.pending_inputs.len() {
+            // let the server know we are still here
+            self.endpoint.send_unreliable(Message::KeepAlive);
+        }
+
+        if let Some(ping) = self.rtt.poll_ping(self.clock.now()) {
+            self.endpoint.send_unreliable(ping);
+        }
+    }
+}
+
+/// Applies `input` the way the server does, snapping to its quantization afterwards.
+fn predict(
+    entity: &mut Entity,
+    input: MovementInput,
+    tick_interval: f32,
+    position_bits: Option<u8>,
+) {
+    entity.applyInput(input, tick_interval);
+    if let Some(bits) = position_bits {
+        entity.x = protocol::quantize(entity.x, bits);
     }
 }
+
+/// Position at `t` (0 to 1) on the cubic Hermite curve from sample `from` to `to`. The
+/// velocity at each sample is the slope from the sample before it, so a curve only depends on
+/// samples that have arrived by the time it is drawn, and each one starts at the speed the
+/// previous one ended with.
+fn hermite(before: Option<(u128, f32)>, from: (u128, f32), to: (u128, f32), t: f32) -> f32 {
+    // per millisecond, samples are always in time order
+    let slope = |a: (u128, f32), b: (u128, f32)| (b.1 - a.1) / (b.0 - a.0) as f32;
+    let span = (to.0 - from.0) as f32;
+    let start_velocity = before.map_or(slope(from, to), |before| slope(before, from));
+    let end_velocity = slope(from, to);
+
+    let t2 = t * t;
+    let t3 = t2 * t;
+    (2.0 * t3 - 3.0 * t2 + 1.0) * from.1
+        + (t3 - 2.0 * t2 + t) * span * start_velocity
+        + (-2.0 * t3 + 3.0 * t2) * to.1
+        + (t3 - t2) * span * end_velocity
+}
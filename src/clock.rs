@@ -0,0 +1,58 @@
+use macroquad::miniquad::date;
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// Source of time for the netcode, so it can run on the wall clock or on simulated time.
+pub trait Clock {
+    /// Seconds elapsed since the clock was started.
+    fn now(&self) -> f64;
+
+    fn now_ms(&self) -> u128 {
+        (self.now() * 1000.0) as u128
+    }
+}
+
+/// Wall clock time, starting at zero when created.
+pub struct RealClock {
+    start: f64,
+}
+
+impl RealClock {
+    pub fn new() -> Self {
+        RealClock { start: date::now() }
+    }
+}
+
+impl Default for RealClock {
+    fn default() -> Self {
+        RealClock::new()
+    }
+}
+
+impl Clock for RealClock {
+    fn now(&self) -> f64 {
+        date::now() - self.start
+    }
+}
+
+/// Time that only moves when told to. Clones share the same time.
+#[derive(Clone, Default)]
+pub struct VirtualClock {
+    now: Rc<Cell<f64>>,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        VirtualClock::default()
+    }
+
+    pub fn advance(&self, seconds: f64) {
+        self.now.set(self.now.get() + seconds);
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> f64 {
+        self.now.get()
+    }
+}
@@ -0,0 +1,276 @@
+use crate::{InputBufferStatus, MovementInput};
+use std::collections::BTreeMap;
+
+/// Furthest ahead of the server, in ticks, an input may be stamped. Anything beyond overruns
+/// the buffer and is dropped.
+pub const INPUT_BUFFER_CAPACITY: u32 = 50;
+
+/// Most ticks of input the buffer aims to hold back, however bad the jitter gets.
+pub const MAX_TARGET_DEPTH: u32 = 8;
+
+/// Ticks without a late input before the target depth is lowered again.
+const SHRINK_AFTER_TICKS: u32 = 250;
+
+/// Holds a client's inputs until the tick they were made for, so the server consumes exactly
+/// one per tick however they clump together on the way. The depth it aims for grows whenever
+/// an input shows up too late and shrinks back slowly once they arrive in time. Ticks that
+/// simply have no input, such as those a client skips when it jumps ahead, don't grow it.
+pub struct InputBuffer {
+    inputs: BTreeMap<u32, MovementInput>,
+    /// Whether an input has arrived in time yet. Until then ticks without one are expected.
+    started: bool,
+    /// Ticks of input the buffer should hold on to beyond the current one.
+    pub target_depth: u32,
+    /// Ticks that came without their input.
+    pub underruns: u32,
+    /// Inputs dropped for being stamped too far ahead.
+    pub overruns: u32,
+    /// Inputs dropped for missing their tick, on arrival or while still held.
+    pub late: u32,
+    /// Newest tick counted in `late`, so the repeats of a late input count once.
+    newest_late_tick: Option<u32>,
+    ticks_since_late: u32,
+    /// Smallest depth since the last status, the closest the buffer came to running dry.
+    min_depth: Option<i32>,
+}
+
+impl Default for InputBuffer {
+    fn default() -> Self {
+        InputBuffer::new()
+    }
+}
+
+impl InputBuffer {
+    pub fn new() -> Self {
+        InputBuffer {
+            inputs: BTreeMap::new(),
+            started: false,
+            target_depth: 1,
+            underruns: 0,
+            overruns: 0,
+            late: 0,
+            newest_late_tick: None,
+            ticks_since_late: 0,
+            min_depth: None,
+        }
+    }
+
+    /// Holds `input` until its tick comes. Inputs for ticks before `tick` are too late, and
+    /// dropped and counted in `late`. Repeats of one already held are dropped too.
+    pub fn insert(&mut self, input: MovementInput, tick: u32) {
+        if input.tick < tick {
+            self.count_late(input.tick);
+            return;
+        }
+        if input.tick > tick + INPUT_BUFFER_CAPACITY {
+            self.overruns += 1;
+            return;
+        }
+        self.started = true;
+        self.inputs.entry(input.tick).or_insert(input);
+    }
+
+    /// Takes the input for `tick`, the only one consumed on it.
+    pub fn take(&mut self, tick: u32) -> Option<MovementInput> {
+        // anything older missed its tick
+        let current = self.inputs.split_off(&tick);
+        for missed in std::mem::replace(&mut self.inputs, current).into_keys() {
+            self.count_late(missed);
+        }
+        let input = self.inputs.remove(&tick);
+        if !self.started {
+            // the client hasn't started sending yet
+            return input;
+        }
+
+        if input.is_none() {
+            self.underruns += 1;
+        }
+        self.ticks_since_late += 1;
+        if self.ticks_since_late >= SHRINK_AFTER_TICKS && self.target_depth > 1 {
+            self.ticks_since_late = 0;
+            self.target_depth -= 1;
+        }
+
+        // ticks of input held beyond this one, -1 once the buffer has run dry
+        let depth = match self.inputs.last_key_value() {
+            Some((&newest, _)) => (newest - tick) as i32,
+            None if input.is_some() => 0,
+            None => -1,
+        };
+        self.min_depth = Some(self.min_depth.map_or(depth, |min| min.min(depth)));
+        input
+    }
+
+    /// Counts an input for `tick` that came too late, once however often it is repeated, and
+    /// holds more inputs back from then on.
+    fn count_late(&mut self, tick: u32) {
+        if self.newest_late_tick.is_some_and(|newest| tick <= newest) {
+            return;
+        }
+        self.newest_late_tick = Some(tick);
+        self.late += 1;
+        self.ticks_since_late = 0;
+        self.target_depth = (self.target_depth + 1).min(MAX_TARGET_DEPTH);
+    }
+
+    /// How the buffer has been doing since the last status, for the client to adjust its
+    /// input rate to.
+    pub fn status(&mut self) -> InputBufferStatus {
+        InputBufferStatus {
+            depth: self.min_depth.take().unwrap_or(0),
+            target_depth: self.target_depth,
+            underruns: self.underruns,
+            overruns: self.overruns,
+            late: self.late,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(tick: u32) -> MovementInput {
+        MovementInput {
+            tick,
+            direction: 1,
+            entity_id: 1,
+            input_sequence_number: tick,
+        }
+    }
+
+    #[test]
+    fn consumes_each_input_once_on_its_tick() {
+        let mut buffer = InputBuffer::new();
+        for tick in [12, 10, 11] {
+            buffer.insert(input(tick), 10);
+        }
+        // a repeat of one already held
+        buffer.insert(input(11), 10);
+
+        assert_eq!(buffer.take(10), Some(input(10)));
+        assert_eq!(buffer.take(10), None);
+        assert_eq!(buffer.take(11), Some(input(11)));
+        assert_eq!(buffer.take(12), Some(input(12)));
+        assert_eq!(buffer.take(13), None);
+        assert_eq!(buffer.underruns, 2);
+    }
+
+    #[test]
+    fn ignores_ticks_before_the_first_input() {
+        let mut buffer = InputBuffer::new();
+        for tick in 0..10 {
+            assert_eq!(buffer.take(tick), None);
+        }
+        assert_eq!(buffer.underruns, 0);
+        assert_eq!(buffer.target_depth, 1);
+    }
+
+    #[test]
+    fn drops_inputs_stamped_too_far_ahead() {
+        let mut buffer = InputBuffer::new();
+        buffer.insert(input(10 + INPUT_BUFFER_CAPACITY), 10);
+        buffer.insert(input(11 + INPUT_BUFFER_CAPACITY), 10);
+        assert_eq!(buffer.overruns, 1);
+        assert_eq!(
+            buffer.take(10 + INPUT_BUFFER_CAPACITY),
+            Some(input(10 + INPUT_BUFFER_CAPACITY))
+        );
+    }
+
+    #[test]
+    fn counts_late_inputs_once_however_often_repeated() {
+        let mut buffer = InputBuffer::new();
+        buffer.insert(input(10), 10);
+        buffer.take(10);
+        // tick 11 passes without its input, which then turns up in every later packet
+        buffer.take(11);
+        for _ in 0..5 {
+            buffer.insert(input(11), 12);
+        }
+        assert_eq!(buffer.late, 1);
+        assert_eq!(buffer.target_depth, 2);
+    }
+
+    #[test]
+    fn counts_inputs_still_held_after_their_tick_as_late() {
+        let mut buffer = InputBuffer::new();
+        buffer.insert(input(10), 10);
+        buffer.insert(input(11), 10);
+        // the server skips past tick 11 without taking it
+        assert_eq!(buffer.take(10), Some(input(10)));
+        assert_eq!(buffer.take(12), None);
+        assert_eq!(buffer.late, 1);
+        // and a repeat of it arriving afterwards counts no more
+        buffer.insert(input(11), 13);
+        assert_eq!(buffer.late, 1);
+    }
+
+    #[test]
+    fn skipped_ticks_do_not_grow_the_target() {
+        let mut buffer = InputBuffer::new();
+        buffer.insert(input(10), 10);
+        buffer.take(10);
+        // the client jumps ahead, leaving ticks 11 to 19 without input
+        buffer.insert(input(20), 11);
+        for tick in 11..20 {
+            assert_eq!(buffer.take(tick), None);
+        }
+        assert_eq!(buffer.take(20), Some(input(20)));
+        assert_eq!(buffer.underruns, 9);
+        assert_eq!(buffer.late, 0);
+        assert_eq!(buffer.target_depth, 1);
+    }
+
+    #[test]
+    fn target_depth_grows_with_late_inputs_and_shrinks_back() {
+        let mut buffer = InputBuffer::new();
+        buffer.insert(input(0), 0);
+        for tick in 1..=MAX_TARGET_DEPTH + 2 {
+            buffer.take(tick - 1);
+            buffer.insert(input(tick - 1), tick);
+        }
+        assert_eq!(buffer.target_depth, MAX_TARGET_DEPTH);
+
+        // inputs arriving in time wind it back down, one step per quiet stretch
+        let start = MAX_TARGET_DEPTH + 2;
+        for tick in start..start + SHRINK_AFTER_TICKS {
+            buffer.insert(input(tick), tick);
+            buffer.take(tick);
+        }
+        assert_eq!(buffer.target_depth, MAX_TARGET_DEPTH - 1);
+        let start = start + SHRINK_AFTER_TICKS;
+        for tick in start..start + SHRINK_AFTER_TICKS * MAX_TARGET_DEPTH {
+            buffer.insert(input(tick), tick);
+            buffer.take(tick);
+        }
+        assert_eq!(buffer.target_depth, 1);
+    }
+
+    #[test]
+    fn depth_follows_the_inputs_held() {
+        let mut buffer = InputBuffer::new();
+        for tick in 10..14 {
+            buffer.insert(input(tick), 10);
+        }
+        buffer.take(10);
+        assert_eq!(buffer.status().depth, 3);
+
+        // it drops as the held inputs run out, not just relative to the newest ever received
+        buffer.take(11);
+        buffer.take(12);
+        buffer.take(13);
+        assert_eq!(buffer.status().depth, 0);
+        buffer.take(14);
+        assert_eq!(buffer.status().depth, -1);
+
+        // the lowest depth since the last status is reported
+        buffer.insert(input(15), 15);
+        buffer.insert(input(16), 15);
+        buffer.take(15);
+        buffer.take(16);
+        assert_eq!(buffer.status().depth, 0);
+        assert_eq!(buffer.status().depth, 0);
+    }
+}
@@ -0,0 +1,314 @@
+// Method and type names mirror the JavaScript original of the demo.
+#![allow(non_snake_case, non_camel_case_types)]
+
+pub mod client;
+pub mod clock;
+pub mod input_buffer;
+pub mod network;
+pub mod protocol;
+pub mod relevancy;
+pub mod reliability;
+pub mod rtt;
+pub mod server;
+pub mod transport;
+pub mod udp;
+
+use std::collections::{HashMap, HashSet};
+
+pub struct Entity {
+    pub x: f32,
+    pub speed: i32,
+    pub entity_id: u32,
+    pub position_buffer: Vec<(u128, f32)>,
+}
+
+impl Entity {
+    pub fn new(entity_id: u32) -> Self {
+        Entity {
+            x: 40.0 + entity_id as f32 * 100.0,
+            speed: 40, // units per second
+            entity_id,
+            position_buffer: Vec::new(),
+        }
+    }
+
+    /// Moves the entity for one tick of `tick_interval` seconds.
+    pub fn applyInput(&mut self, input: MovementInput, tick_interval: f32) {
+        self.x += input.direction as f32 * tick_interval * self.speed as f32;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Movement(MovementInput),
+    WorldState(WorldStateMessage),
+    /// Client asks to join. Repeated until the server answers with a `Challenge`.
+    ConnectRequest,
+    /// Server asks the client to echo `salt`, proving it really receives at its address.
+    Challenge {
+        salt: u32,
+    },
+    ChallengeResponse {
+        salt: u32,
+    },
+    /// Server lets the client in, with the tick it is on and the length of a tick in seconds,
+    /// which the client then simulates at too.
+    Accepted {
+        entity_id: u32,
+        tick: u32,
+        tick_interval: f32,
+    },
+    /// Server turns the client away because it has no room for another one.
+    Denied,
+    /// Client has nothing else to send but is still there.
+    KeepAlive,
+    /// Client is leaving. The server removes its entity straight away instead of waiting
+    /// for it to time out.
+    Disconnect,
+    /// Either end asks for a `Pong` with the same id, to measure the round trip time.
+    Ping {
+        id: u32,
+    },
+    Pong {
+        id: u32,
+    },
+    /// Server tells the client how its inputs are arriving. Inputs for a tick the server has
+    /// already simulated are dropped, not applied late, and counted in `late`, so a client
+    /// seeing that count rise should stamp its inputs further ahead.
+    InputBuffer(InputBufferStatus),
+    /// Client could not resolve delta snapshot `sequence`, its baseline was gone. The packet it
+    /// came in is acked all the same, so the server must not build on it or anything older.
+    SnapshotNack {
+        sequence: u32,
+    },
+}
+
+/// What actually travels between the two ends of a connection: a header acknowledging the
+/// packets received from the other end, and the messages of both channels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub sequence: u16,
+    /// Newest packet received from the other end, `None` until one has arrived.
+    pub ack: Option<u16>,
+    /// Bit `n` is set if packet `ack - 1 - n` was received as well.
+    pub ack_bits: u32,
+    /// Reliable-ordered messages with their message ids, repeated until acknowledged.
+    pub reliable: Vec<(u16, Message)>,
+    pub unreliable: Vec<Message>,
+}
+
+#[cfg(test)]
+impl Packet {
+    /// A packet with no messages that acks nothing, for tests to fill in.
+    pub(crate) fn empty(sequence: u16) -> Self {
+        Packet {
+            sequence,
+            ack: None,
+            ack_bits: 0,
+            reliable: Vec::new(),
+            unreliable: Vec::new(),
+        }
+    }
+}
+
+/// Everything `receive` hands over until it runs dry, for tests.
+#[cfg(test)]
+pub(crate) fn drain<T>(receive: impl FnMut() -> Option<T>) -> Vec<T> {
+    std::iter::from_fn(receive).collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MovementInput {
+    /// Server tick the input is meant to be applied on.
+    pub tick: u32,
+    /// Which way the entity moves for the tick: -1 left, 1 right or 0 to stand still. The
+    /// server supplies the length of the tick, so a client can't move further than it should.
+    pub direction: i8,
+    pub entity_id: u32,
+    pub input_sequence_number: u32,
+}
+
+/// State of the server's buffer of a client's inputs, sent along with its snapshots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputBufferStatus {
+    /// Fewest ticks of input the buffer held beyond the current tick since the last status.
+    /// Negative while inputs arrive after their tick.
+    pub depth: i32,
+    /// Depth the server wants the client to keep the buffer at.
+    pub target_depth: u32,
+    /// Ticks so far that came without their input.
+    pub underruns: u32,
+    /// Inputs dropped so far for being stamped too far ahead.
+    pub overruns: u32,
+    /// Inputs dropped so far for arriving after their tick.
+    pub late: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct world_state {
+    pub entity_id: u32,
+    pub position: f32,
+    pub last_processed_input: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorldStateMessage {
+    pub sequence: u32,
+    /// Server tick the snapshot was taken on, and the server's time then in milliseconds.
+    pub tick: u32,
+    pub time_ms: u32,
+    /// Snapshot this one is a delta against. `world_state` then only holds the entities that
+    /// changed since it. `None` means `world_state` is complete.
+    pub baseline: Option<u32>,
+    /// Fractional bits positions are quantized to, `None` for raw floats.
+    pub position_bits: Option<u8>,
+    /// Whether quantized positions go on the wire as floats, because one of them is outside
+    /// the range the fixed-point encoding covers.
+    pub float_positions: bool,
+    /// Entities in the baseline that no longer exist.
+    pub removed: Vec<u32>,
+    pub world_state: Vec<world_state>,
+}
+
+impl WorldStateMessage {
+    /// Builds the message for `current`, leaving out entities unchanged since `baseline`.
+    pub fn delta(
+        sequence: u32,
+        tick: u32,
+        time_ms: u32,
+        current: &[world_state],
+        baseline: Option<(u32, &[world_state])>,
+        position_bits: Option<u8>,
+    ) -> Self {
+        let (world_state, removed) = match baseline {
+            None => (current.to_vec(), Vec::new()),
+            Some((_, baseline)) => {
+                // keyed by entity, so large worlds don't compare every pair of entities
+                let old: HashMap<u32, &world_state> =
+                    baseline.iter().map(|s| (s.entity_id, s)).collect();
+                let present: HashSet<u32> = current.iter().map(|s| s.entity_id).collect();
+                (
+                    current
+                        .iter()
+                        .filter(|state| old.get(&state.entity_id).copied() != Some(*state))
+                        .cloned()
+                        .collect(),
+                    baseline
+                        .iter()
+                        .map(|old| old.entity_id)
+                        .filter(|id| !present.contains(id))
+                        .collect(),
+                )
+            }
+        };
+
+        WorldStateMessage {
+            sequence,
+            tick,
+            time_ms,
+            baseline: baseline.map(|(baseline_sequence, _)| baseline_sequence),
+            position_bits,
+            float_positions: false,
+            removed,
+            world_state,
+        }
+    }
+
+    /// Rebuilds the complete world state by applying this delta on top of `baseline`.
+    pub fn apply_to(&self, baseline: &[world_state]) -> Vec<world_state> {
+        let removed: HashSet<u32> = self.removed.iter().copied().collect();
+        let mut full: Vec<world_state> = baseline
+            .iter()
+            .filter(|s| !removed.contains(&s.entity_id))
+            .cloned()
+            .collect();
+        let mut index: HashMap<u32, usize> = full
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (s.entity_id, i))
+            .collect();
+        for state in &self.world_state {
+            match index.get(&state.entity_id) {
+                Some(&i) => full[i] = state.clone(),
+                None => {
+                    index.insert(state.entity_id, full.len());
+                    full.push(state.clone());
+                }
+            }
+        }
+        full
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(entity_id: u32, position: f32) -> world_state {
+        world_state {
+            entity_id,
+            position,
+            last_processed_input: 0.0,
+        }
+    }
+
+    fn round_trip(baseline: &[world_state], current: &[world_state]) -> WorldStateMessage {
+        let delta = WorldStateMessage::delta(2, 2, 40, current, Some((1, baseline)), None);
+        assert_eq!(delta.baseline, Some(1));
+        assert_eq!(delta.apply_to(baseline), current);
+        delta
+    }
+
+    #[test]
+    fn full_snapshots_hold_everything() {
+        let current = [state(1, 10.0), state(2, 20.0)];
+        let full = WorldStateMessage::delta(1, 1, 20, &current, None, Some(4));
+        assert_eq!(full.baseline, None);
+        assert!(full.removed.is_empty());
+        assert_eq!(full.world_state, current);
+        assert_eq!(full.apply_to(&[]), current);
+    }
+
+    #[test]
+    fn unchanged_world_gives_an_empty_delta() {
+        let world = [state(1, 10.0), state(2, 20.0)];
+        let delta = round_trip(&world, &world);
+        assert!(delta.world_state.is_empty());
+        assert!(delta.removed.is_empty());
+    }
+
+    #[test]
+    fn delta_holds_only_changed_entities() {
+        let baseline = [state(1, 10.0), state(2, 20.0), state(3, 30.0)];
+        let mut current = baseline.clone();
+        current[1].position = 21.0;
+        current[2].last_processed_input = 5.0;
+        let delta = round_trip(&baseline, &current);
+        assert_eq!(delta.world_state, [current[1].clone(), current[2].clone()]);
+        assert!(delta.removed.is_empty());
+    }
+
+    #[test]
+    fn delta_adds_and_removes_entities() {
+        let baseline = [state(1, 10.0), state(2, 20.0), state(3, 30.0)];
+        let current = [state(1, 10.0), state(3, 31.0), state(4, 40.0)];
+        let delta = round_trip(&baseline, &current);
+        assert_eq!(delta.world_state, [state(3, 31.0), state(4, 40.0)]);
+        assert_eq!(delta.removed, [2]);
+    }
+
+    #[test]
+    fn delta_against_an_empty_baseline_adds_everything() {
+        let current = [state(1, 10.0), state(2, 20.0)];
+        let delta = round_trip(&[], &current);
+        assert_eq!(delta.world_state, current);
+    }
+
+    #[test]
+    fn delta_to_an_empty_world_removes_everything() {
+        let baseline = [state(1, 10.0), state(2, 20.0)];
+        let delta = round_trip(&baseline, &[]);
+        assert!(delta.world_state.is_empty());
+        assert_eq!(delta.removed, [1, 2]);
+    }
+}
@@ -1,212 +1,450 @@
-mod client;
-mod server;
-
-use crate::client::Client;
-use crate::server::Server;
 use macroquad::math::f32;
 use macroquad::prelude::*;
-use macroquad::ui::{
-    hash, root_ui,
-    widgets::{self, Group},
-    Drag, Ui,
-};
+use macroquad::ui::{hash, root_ui, widgets, Ui};
+use netcode_example::client::{Client, RemoteEntityMode};
+use netcode_example::clock::{Clock, RealClock, VirtualClock};
+use netcode_example::network::{LagNetwork, LinkConditions};
+use netcode_example::protocol::{Quantization, MAX_PRECISION_BITS};
+use netcode_example::server::Server;
+use netcode_example::udp::{UdpListener, UdpTransport};
 use std::cell::{RefCell, RefMut};
 use std::rc::Rc;
 
-fn get_time_ms() -> u128 {
-    (get_time() * 1000.0) as u128
-}
+// function for drawing things on the screen
+fn draw_client_entities(
+    client: RefMut<Client>,
+    link: Option<&LagNetwork>,
+    controls: &str,
+    y_offset: f32,
+) {
+    let player_colour = if client.entity_id == 1 { BLUE } else { RED };
 
-struct Entity {
-    x: f32,
-    speed: i32,
-    entity_id: u32,
-    position_buffer: Vec<(u128, f32)>,
-}
+    // draw outline rectangle, with a row of text per line above the entities, all clear of
+    // the Settings window
+    draw_rectangle_lines(
+        10.0,
+        y_offset - 145.0,
+        screen_width() - 20.0,
+        200.0,
+        2.0,
+        player_colour,
+    );
+    let mut rows = Vec::new();
+
+    rows.push(format!(
+        "Player {} view ({}) - {:?}",
+        client.entity_id, controls, client.state
+    ));
+
+    // how the server's buffer of our inputs is doing
+    if let Some(status) = client.input_buffer {
+        rows.push(format!(
+            "Input buffer {}/{} ticks, {} under, {} over, {} late",
+            status.depth, status.target_depth, status.underruns, status.overruns, status.late
+        ));
+    }
 
-impl Entity {
-    fn new(entity_id: u32) -> Self {
-        Entity {
-            x: 40.0 + entity_id as f32 * 100.0,
-            speed: 40000,
-            entity_id,
-            position_buffer: Vec::new(),
+    // write the number of non-acknowledged messages
+    rows.push(format!(
+        "Non-acknowledged messages: {}",
+        client.pending_inputs.len()
+    ));
+
+    let mut rtt = match client.rtt.smoothed {
+        Some(smoothed) => format!(
+            "RTT: {:.0} ms (var {:.0} ms)",
+            smoothed * 1000.0,
+            client.rtt.variance * 1000.0
+        ),
+        None => "RTT: measuring...".to_string(),
+    };
+    if let Some(offset) = client.server_time_offset {
+        rtt += &format!(", clock {:+.0} ms", offset * 1000.0);
+    }
+    rows.push(rtt);
+
+    // traffic over the last second, per direction
+    if let Some(link) = link {
+        let up = link.outbound.borrow();
+        let down = link.inbound.borrow();
+        let (up_window, down_window) = (up.window_stats(), down.window_stats());
+        for (label, stats) in [("Up", up_window), ("Down", down_window)] {
+            rows.push(format!(
+                "{}: {} pkt/s, {} msg/s, {} B/s, {:.0} ms queued",
+                label, stats.packets, stats.messages, stats.bytes, stats.average_delay_ms
+            ));
         }
+        rows.push(format!(
+            "Lost/s: {} up, {} down (total {} up, {} down)",
+            up_window.dropped, down_window.dropped, up.stats.dropped, down.stats.dropped
+        ));
     }
 
-    fn applyInput(&mut self, input: MovementInput) {
-        self.x += input.press_time * self.speed as f32;
+    for (i, row) in rows.iter().enumerate() {
+        draw_text(
+            row,
+            20.0,
+            y_offset - 125.0 + i as f32 * 20.0,
+            20.0,
+            DARKGRAY,
+        );
     }
-}
 
-#[derive(Debug)]
-enum Message {
-    Movement(MovementInput),
-    WorldState(WorldStateMessage),
+    for entity in client.entities.values() {
+        let entity_colour = if entity.entity_id == 1 { BLUE } else { RED };
+        draw_rectangle(
+            client.render_x(entity),
+            y_offset + 20.0,
+            20.0,
+            20.0,
+            entity_colour,
+        );
+    }
 }
 
-#[derive(Debug)]
-struct MovementInput {
-    press_time: f32,
-    entity_id: u32,
-    input_sequence_number: u32,
-}
+// `links` are the client ends of the in-process connections, by entity id
+fn draw_server_perspective(s: &Server, links: &[(u32, &LagNetwork)]) {
+    draw_rectangle_lines(10.0, 220.0, screen_width() - 20.0, 120.0, 2.0, DARKGRAY);
 
-impl MovementInput {
-    pub(crate) fn clone(&self) -> MovementInput {
-        MovementInput {
-            press_time: self.press_time,
-            entity_id: self.entity_id,
-            input_sequence_number: self.input_sequence_number,
+    for entity in s.entities.values() {
+        // Draw the entity as a rectangle
+        let mut colour = RED;
+        if entity.entity_id == 1 {
+            colour = BLUE;
         }
+        draw_rectangle(entity.x, 260.0, 20.0, 20.0, colour);
     }
-}
 
-#[derive(Debug, Clone)]
-struct world_state {
-    entity_id: u32,
-    position: f32,
-    last_processed_input: f32,
-}
+    draw_text(
+        format!(
+            "Last Acknowledged: Player 0 - {} Player 1 - {}",
+            s.last_processed_inputs.get(&1).unwrap_or(&0.0),
+            s.last_processed_inputs.get(&2).unwrap_or(&0.0)
+        ),
+        20.0,
+        240.0,
+        20.0,
+        DARKGRAY,
+    );
 
-#[derive(Debug, Clone)]
-struct WorldStateMessage {
-    world_state: Vec<world_state>,
-}
+    if let Some(quantization) = &s.quantization {
+        let saved: usize = s
+            .connections
+            .iter()
+            .map(|c| {
+                c.unquantized_snapshot_bytes
+                    .saturating_sub(c.snapshot_bytes)
+            })
+            .sum();
+        draw_text(
+            format!(
+                "Quantization: {} bits, {} bytes saved per tick, max error {:.4}",
+                quantization.precision_bits, saved, s.quantization_error
+            ),
+            20.0,
+            298.0,
+            20.0,
+            DARKGRAY,
+        );
+    }
 
-struct NetworkMessage {
-    receive_time: u128,
-    payload: Message,
+    for (i, (entity_id, link)) in links.iter().enumerate() {
+        let inbound = link.outbound.borrow();
+        let (snapshot_bytes, snapshot_interval) = s
+            .connections
+            .iter()
+            .find(|c| c.entity_id == *entity_id)
+            .map_or((0, 0.0), |c| (c.snapshot_bytes, c.snapshot_interval));
+        draw_text(
+            format!(
+                "Player {} inbound: {} delivered, {} dropped, {} in flight - snapshot {} bytes every {:.0} ms",
+                entity_id,
+                inbound.stats.delivered,
+                inbound.stats.dropped,
+                inbound.in_flight(),
+                snapshot_bytes,
+                snapshot_interval * 1000.0
+            ),
+            20.0,
+            316.0 + i as f32 * 18.0,
+            20.0,
+            DARKGRAY,
+        );
+    }
 }
 
-struct LagNetwork {
-    messages: Vec<NetworkMessage>,
-}
+// sliders for one direction of a client's link
+fn draw_link_settings(ui: &mut Ui, id: u64, link: &mut LinkConditions) {
+    ui.label(None, &format!("Lag: {}", link.latency_ms));
+    ui.slider(
+        hash!(id, "lag"),
+        "[5 .. 500]",
+        5f32..5000f32,
+        &mut link.latency_ms,
+    );
 
-impl LagNetwork {
-    fn send(&mut self, lag_ms: f32, message: Message) {
-        let in_ms = get_time_ms();
+    ui.label(None, &format!("Loss: {:.0}%", link.packet_loss * 100.0));
+    ui.slider(
+        hash!(id, "loss"),
+        "[0 .. 1]",
+        0f32..1f32,
+        &mut link.packet_loss,
+    );
 
-        //set recv time to time now + lag_ms
-        let receive_time = in_ms + lag_ms as u128;
+    ui.label(None, &format!("Jitter: {}", link.jitter));
+    if ui.button(None, "Cycle Jitter Model") {
+        link.jitter = link.jitter.next();
+    }
+    if let Some(amount) = link.jitter.amount_mut() {
+        ui.slider(hash!(id, "jitter"), "[0 .. 500]", 0f32..500f32, amount);
+    }
 
-        // make the NetworkMessage
-        let network_message = NetworkMessage {
-            receive_time: receive_time,
-            payload: message,
-        };
+    ui.label(None, &format!("Reorder: {:.0}%", link.reorder * 100.0));
+    ui.slider(
+        hash!(id, "reorder"),
+        "[0 .. 1]",
+        0f32..1f32,
+        &mut link.reorder,
+    );
 
-        self.messages.push(network_message);
+    ui.label(None, &format!("Duplicate: {:.0}%", link.duplicate * 100.0));
+    ui.slider(
+        hash!(id, "duplicate"),
+        "[0 .. 1]",
+        0f32..1f32,
+        &mut link.duplicate,
+    );
+}
+
+fn draw_quantization_settings(ui: &mut Ui, quantization: &mut Option<Quantization>) {
+    ui.label(None, &format!("Quantization?: {}", quantization.is_some()));
+    if ui.button(None, "Toggle Quantization") {
+        *quantization = match quantization {
+            Some(_) => None,
+            None => Some(Quantization::default()),
+        };
     }
+    if let Some(quantization) = quantization {
+        let mut bits = quantization.precision_bits as f32;
+        ui.slider(
+            hash!(),
+            "Precision bits",
+            0f32..MAX_PRECISION_BITS as f32,
+            &mut bits,
+        );
+        quantization.precision_bits = bits.round() as u8;
+    }
+}
 
-    fn receive(&mut self) -> Option<Message> {
-        if self.messages.len() == 0 {
-            return None;
-        }
+fn draw_client_settings(ui: &mut Ui, label: &str, c: &mut Client) {
+    ui.label(None, &format!("{} Entity ID: {}", label, c.entity_id));
+    ui.label(None, &format!("Prediction?: {}", c.client_side_prediction));
+    ui.label(
+        None,
+        &format!("Reconciliation?: {}", c.server_reconciliation),
+    );
+    ui.label(None, &format!("Remote entities: {}", c.remote_entity_mode));
+    if ui.button(None, "Toggle Prediction") {
+        c.client_side_prediction = !c.client_side_prediction;
+    }
+    if ui.button(None, "Toggle Reconciliation") {
+        c.server_reconciliation = !c.server_reconciliation;
+    }
+    if ui.button(None, "Cycle Remote Entities") {
+        c.remote_entity_mode = c.remote_entity_mode.next();
+    }
+    if let RemoteEntityMode::Extrapolate {
+        max_time,
+        blend_time,
+    } = &mut c.remote_entity_mode
+    {
+        ui.label(
+            None,
+            &format!("Max extrapolation: {:.0} ms", *max_time * 1000.0),
+        );
+        ui.slider(
+            hash!(label, "max extrapolation"),
+            "[0 .. 1]",
+            0f32..1f32,
+            max_time,
+        );
+        ui.label(None, &format!("Blend time: {:.0} ms", *blend_time * 1000.0));
+        ui.slider(
+            hash!(label, "blend time"),
+            "[0 .. 1]",
+            0f32..1f32,
+            blend_time,
+        );
+    }
+    ui.label(
+        None,
+        &format!("Smooth corrections?: {}", c.smooth_corrections),
+    );
+    if ui.button(None, "Toggle Smooth corrections") {
+        c.smooth_corrections = !c.smooth_corrections;
+    }
+    if c.smooth_corrections {
+        ui.label(
+            None,
+            &format!("Correction time: {:.0} ms", c.correction_time * 1000.0),
+        );
+        ui.slider(
+            hash!(label, "correction time"),
+            "[0 .. 1]",
+            0f32..1f32,
+            &mut c.correction_time,
+        );
+        ui.label(
+            None,
+            &format!("Snap above: {:.0}", c.correction_snap_distance),
+        );
+        ui.slider(
+            hash!(label, "snap distance"),
+            "[0 .. 200]",
+            0f32..200f32,
+            &mut c.correction_snap_distance,
+        );
+    }
+    if c.is_active() && ui.button(None, "Disconnect") {
+        c.disconnect();
+    }
+}
 
-        for (i, v) in self.messages.iter().enumerate() {
-            let in_ms = get_time_ms();
+enum Mode {
+    /// Server and both clients in this process, connected through `LagNetwork`s.
+    Local,
+    /// Like `Local`, but also accepts clients over UDP on the given address.
+    Listen(String),
+    /// A single client talking to a server over UDP.
+    Connect(String),
+}
 
-            if in_ms >= v.receive_time {
-                let message = self.messages.remove(i);
-                return Some(message.payload);
-            }
-        }
-        return None;
+fn parse_args() -> Mode {
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("--listen") => Mode::Listen(args.get(2).cloned().unwrap_or("0.0.0.0:7777".into())),
+        Some("--connect") => Mode::Connect(args.get(2).cloned().unwrap_or("127.0.0.1:7777".into())),
+        _ => Mode::Local,
     }
 }
 
-fn draw_coloured_rectangle(x: f32, y: f32, width: f32, height: f32, colour: Color) {
-    // draw with line thickness of 2.0
-    draw_line(x, y, x + width, y, 2.0, colour);
-}
+// a single client connected to a server in another process
+async fn run_remote_client(addr: String) {
+    let transport = UdpTransport::connect(&addr).expect("failed to open UDP socket");
+    println!("Connecting to {}", addr);
 
-// function for drawing things on the screen
-fn draw_client_entities(client: RefMut<Client>, y_offset: f32) {
-    let player_colour = if client.entity_id == 1 { BLUE } else { RED };
+    let client = Rc::new(RefCell::new(Client::new(
+        0.02,
+        Rc::new(RealClock::new()),
+        Box::new(transport),
+    )));
 
-    // draw outline rectangle
-    draw_rectangle_lines(
-        10.0,
-        y_offset - 65.0,
-        screen_width() - 20.0,
-        120.0,
-        2.0,
-        player_colour,
-    );
+    // leave properly when the window is closed, rather than leaving the server to time out
+    prevent_quit();
 
-    let mut move_message = "move with LEFT and RIGHT arrow keys";
-    if client.entity_id == 2 {
-        move_message = "move with A and D keys"
-    }
+    loop {
+        if is_quit_requested() {
+            client.borrow_mut().disconnect();
+            return;
+        }
 
-    draw_text(
-        &format!("Player {} view - {}", client.entity_id, move_message),
-        20.0,
-        y_offset - 40.0,
-        20.0,
-        DARKGRAY,
-    );
+        let delta_time = get_frame_time();
 
-    // write the number of non-acknowledged messages
-    draw_text(
-        &format!("Non-acknowledged messages: {}", client.pending_inputs.len()),
-        20.0,
-        y_offset - 20.0,
-        20.0,
-        DARKGRAY,
-    );
+        {
+            let mut client = client.borrow_mut();
+            client.key_left = is_key_down(KeyCode::Left);
+            client.key_right = is_key_down(KeyCode::Right);
+        }
 
-    for (id, entity) in client.entities.iter() {
-        let entity_colour = if entity.entity_id == 1 { BLUE } else { RED };
-        draw_rectangle(entity.x, y_offset + 20.0, 20.0, 20.0, entity_colour);
-    }
-}
+        clear_background(LIGHTGRAY);
 
-fn draw_server_perspective(s: RefMut<Server>) {
-    draw_rectangle_lines(10.0, 220.0, screen_width() - 20.0, 120.0, 2.0, DARKGRAY);
+        draw_client_entities(
+            client.borrow_mut(),
+            None,
+            "LEFT and RIGHT arrow keys",
+            160.0,
+        );
 
-    for (id, entity) in s.entities.iter() {
-        // Draw the entity as a rectangle
-        let mut colour = RED;
-        if entity.entity_id == 1 {
-            colour = BLUE;
+        {
+            let client_ui = client.clone();
+            widgets::Window::new(hash!(), vec2(500., 20.), vec2(200., 500.))
+                .label("Settings")
+                .titlebar(true)
+                .ui(&mut root_ui(), move |ui| {
+                    draw_client_settings(ui, "Client", &mut client_ui.borrow_mut());
+                });
         }
-        draw_rectangle(entity.x, 260.0, 20.0, 20.0, colour);
-    }
 
-    draw_text(
-        &format!(
-            "Last Acknowledged: Player 0 - {} Player 1 - {}",
-            s.last_processed_inputs.get(&1).unwrap_or(&0.0),
-            s.last_processed_inputs.get(&2).unwrap_or(&0.0)
-        ),
-        20.0,
-        240.0,
-        20.0,
-        DARKGRAY,
-    );
+        client.borrow_mut().update(delta_time);
+
+        next_frame().await;
+    }
 }
 
 #[macroquad::main("Netcode Example")]
 async fn main() {
+    let mode = parse_args();
+    let listen_addr = match mode {
+        Mode::Local => None,
+        Mode::Listen(addr) => Some(addr),
+        Mode::Connect(addr) => return run_remote_client(addr).await,
+    };
+
+    // Locally, time is simulated and advanced by the frame time scaled by the speed setting.
+    // Remote clients can't be slowed down, so the wall clock is used when listening.
+    let virtual_clock = VirtualClock::new();
+    let clock: Rc<dyn Clock> = match listen_addr {
+        None => Rc::new(virtual_clock.clone()),
+        Some(_) => Rc::new(RealClock::new()),
+    };
+    let mut simulation_speed = 1.0f32;
+
+    let mut listener = listen_addr.map(|addr| {
+        let listener = UdpListener::bind(&addr).expect("failed to bind UDP socket");
+        println!("Listening on {}", listener.local_addr().unwrap());
+        listener
+    });
+
     // Create a server
-    let server = Server::new();
+    let mut server = Server::new();
 
     // Add two clients to the server
-    let client1 = Server::add_client(server.clone());
-    let client2 = Server::add_client(server.clone());
+    let initial_conditions = LinkConditions {
+        latency_ms: 250.0,
+        ..LinkConditions::default()
+    };
+    let (link1, server_end1) = LagNetwork::pair(clock.clone(), 1, initial_conditions);
+    let (link2, server_end2) = LagNetwork::pair(clock.clone(), 2, initial_conditions);
+    server.connect(Box::new(server_end1));
+    server.connect(Box::new(server_end2));
+    let client1 = Rc::new(RefCell::new(Client::new(
+        0.02, // 20 ms
+        clock.clone(),
+        Box::new(link1.clone()),
+    )));
+    let client2 = Rc::new(RefCell::new(Client::new(
+        0.02, // 20 ms
+        clock.clone(),
+        Box::new(link2.clone()),
+    )));
 
     // List the clients (for debugging)
-    server.borrow().list_clients();
-
-    // connect the two clients
+    server.list_clients();
 
     loop {
         // Get the last frame time
-        let delta_time = get_frame_time();
+        let delta_time = get_frame_time() * simulation_speed;
+        virtual_clock.advance(delta_time as f64);
         // println!("Delta time: {}", delta_time);
 
+        // accept clients from other processes
+        if let Some(listener) = &mut listener {
+            for peer in listener.poll() {
+                println!("Client connecting from {}", peer.addr());
+                server.connect(Box::new(peer));
+            }
+        }
+
         //check for left and right arrow key press
         {
             let mut client1 = client1.borrow_mut();
@@ -224,62 +462,82 @@ async fn main() {
         // Clear the screen for each frame
         clear_background(LIGHTGRAY);
 
-        {
-            let mut server = server.borrow_mut();
-            draw_server_perspective(server);
-        }
+        draw_server_perspective(
+            &server,
+            &[
+                (client1.borrow().entity_id, &link1),
+                (client2.borrow().entity_id, &link2),
+            ],
+        );
 
         {
-            let mut client1 = client1.borrow_mut();
-            draw_client_entities(client1, 120.0);
+            let client1 = client1.borrow_mut();
+            draw_client_entities(client1, Some(&link1), "LEFT and RIGHT arrow keys", 160.0);
         }
 
         {
-            let mut client2 = client2.borrow_mut();
-            draw_client_entities(client2, 450.0);
+            let client2 = client2.borrow_mut();
+            draw_client_entities(client2, Some(&link2), "A and D keys", 495.0);
         }
 
         {
             let client1_ui = client1.clone();
             let client2_ui = client2.clone();
+            let links = [link1.clone(), link2.clone()];
+            let simulation_speed = match listener {
+                None => Some(&mut simulation_speed),
+                Some(_) => None,
+            };
+            let quantization = &mut server.quantization;
+            let connections = &mut server.connections;
             widgets::Window::new(hash!(), vec2(500., 20.), vec2(200., 500.))
                 .label("Settings")
                 .titlebar(true)
-                .ui(&mut *root_ui(), move |ui| {
-                    let mut client = client1_ui.borrow_mut(); // RefMut here
-                    let mut client2 = client2_ui.borrow_mut(); // RefMut here
-
-                    for (mut c, label) in vec![(client, "Client 1"), (client2, "Client 2")] {
-                        ui.label(None, &format!("{} Entity ID: {}", label, c.entity_id));
-                        ui.label(None, &format!("Prediction?: {}", c.client_side_prediction));
-                        ui.label(
-                            None,
-                            &format!("Reconciliation?: {}", c.server_reconciliation),
-                        );
-                        ui.label(None, &format!("Interpolation: {}", c.entity_interpolation));
-                        if ui.button(None, "Toggle Prediction") {
-                            c.client_side_prediction = !c.client_side_prediction;
-                        }
-                        if ui.button(None, "Toggle Reconciliation") {
-                            c.server_reconciliation = !c.server_reconciliation;
-                        }
-                        if ui.button(None, "Toggle Interpolation") {
-                            c.entity_interpolation = !c.entity_interpolation;
+                .ui(&mut root_ui(), move |ui| {
+                    if let Some(simulation_speed) = simulation_speed {
+                        ui.label(None, &format!("Speed: {:.2}x", simulation_speed));
+                        ui.slider(hash!(), "[0.1 .. 2]", 0.1f32..2f32, simulation_speed);
+                    }
+                    draw_quantization_settings(ui, quantization);
+
+                    let client = client1_ui.borrow_mut(); // RefMut here
+                    let client2 = client2_ui.borrow_mut(); // RefMut here
+
+                    for ((mut c, link), label) in [client, client2]
+                        .into_iter()
+                        .zip(links)
+                        .zip(["Client 1", "Client 2"])
+                    {
+                        draw_client_settings(ui, label, &mut c);
+                        let relevancy = connections
+                            .iter_mut()
+                            .find(|connection| connection.entity_id == c.entity_id)
+                            .map(|connection| &mut connection.relevancy);
+                        if let Some(radius) = relevancy.and_then(|r| r.radius_mut()) {
+                            ui.label(None, &format!("Relevancy radius: {:.0}", radius));
+                            ui.slider(
+                                hash!(label, "relevancy"),
+                                "[0 .. 1000]",
+                                0f32..1000f32,
+                                radius,
+                            );
                         }
-                        ui.label(None, &format!("Lag: {}", c.latency_to_server));
-
-                        ui.slider(
-                            hash!(label),
-                            "[5 .. 500]",
-                            5f32..5000f32,
-                            &mut c.latency_to_server,
-                        );
+                        ui.tree_node(hash!(label, "uplink"), "Uplink", |ui| {
+                            let conditions = &mut link.outbound.borrow_mut().conditions;
+                            draw_link_settings(ui, hash!(label, "uplink"), conditions);
+                        });
+                        ui.tree_node(hash!(label, "downlink"), "Downlink", |ui| {
+                            let conditions = &mut link.inbound.borrow_mut().conditions;
+                            draw_link_settings(ui, hash!(label, "downlink"), conditions);
+                        });
                     }
                 });
         }
 
         // Update server and clients at their respective intervals
-        server.borrow_mut().update(delta_time);
+        client1.borrow_mut().update(delta_time);
+        client2.borrow_mut().update(delta_time);
+        server.update(delta_time);
 
         // Wait for the next frame
         next_frame().await;
@@ -0,0 +1,431 @@
+use crate::clock::Clock;
+use crate::protocol;
+use crate::transport::Transport;
+use crate::Packet;
+use macroquad::rand::RandGenerator;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+use std::f32::consts::PI;
+use std::fmt;
+use std::rc::Rc;
+
+/// Random extra delay added on top of the base latency of a link.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Jitter {
+    None,
+    /// Evenly spread between 0 and `max_ms`.
+    Uniform {
+        max_ms: f32,
+    },
+    /// Bell curve around the base latency, clamped so packets never arrive early.
+    Normal {
+        std_dev_ms: f32,
+    },
+    /// Mostly small delays with occasional large spikes.
+    Pareto {
+        scale_ms: f32,
+        shape: f32,
+    },
+}
+
+impl Jitter {
+    /// Draws an extra delay in milliseconds. The result may be negative for `Normal`.
+    fn sample(&self, rng: &RandGenerator) -> f32 {
+        match *self {
+            Jitter::None => 0.0,
+            Jitter::Uniform { max_ms } => rng.gen_range(0.0, max_ms),
+            Jitter::Normal { std_dev_ms } => {
+                // Box-Muller transform
+                let u1: f32 = rng.gen_range(f32::EPSILON, 1.0);
+                let u2: f32 = rng.gen_range(0.0, 1.0);
+                (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos() * std_dev_ms
+            }
+            Jitter::Pareto { scale_ms, shape } => {
+                let u: f32 = rng.gen_range(f32::EPSILON, 1.0);
+                scale_ms / u.powf(1.0 / shape) - scale_ms
+            }
+        }
+    }
+
+    /// Cycles to the next model, keeping the current magnitude where it makes sense.
+    pub fn next(&self) -> Jitter {
+        let amount = self.amount().unwrap_or(50.0);
+        match self {
+            Jitter::None => Jitter::Uniform { max_ms: amount },
+            Jitter::Uniform { .. } => Jitter::Normal { std_dev_ms: amount },
+            Jitter::Normal { .. } => Jitter::Pareto {
+                scale_ms: amount,
+                shape: 1.5,
+            },
+            Jitter::Pareto { .. } => Jitter::None,
+        }
+    }
+
+    fn amount(&self) -> Option<f32> {
+        match *self {
+            Jitter::None => None,
+            Jitter::Uniform { max_ms } => Some(max_ms),
+            Jitter::Normal { std_dev_ms } => Some(std_dev_ms),
+            Jitter::Pareto { scale_ms, .. } => Some(scale_ms),
+        }
+    }
+
+    /// The main magnitude of the model in milliseconds, for editing from the UI.
+    pub fn amount_mut(&mut self) -> Option<&mut f32> {
+        match self {
+            Jitter::None => None,
+            Jitter::Uniform { max_ms } => Some(max_ms),
+            Jitter::Normal { std_dev_ms } => Some(std_dev_ms),
+            Jitter::Pareto { scale_ms, .. } => Some(scale_ms),
+        }
+    }
+}
+
+impl fmt::Display for Jitter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Jitter::None => write!(f, "None"),
+            Jitter::Uniform { max_ms } => write!(f, "Uniform 0-{:.0}ms", max_ms),
+            Jitter::Normal { std_dev_ms } => write!(f, "Normal sd {:.0}ms", std_dev_ms),
+            Jitter::Pareto { scale_ms, shape } => {
+                write!(f, "Pareto {:.0}ms a={:.1}", scale_ms, shape)
+            }
+        }
+    }
+}
+
+/// How one direction of a link delays and mangles the traffic sent over it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkConditions {
+    /// Base one-way delay in milliseconds.
+    pub latency_ms: f32,
+    /// Probability in `[0, 1]` that a packet is lost.
+    pub packet_loss: f32,
+    pub jitter: Jitter,
+    /// Probability in `[0, 1]` that a packet is held back so later packets overtake it.
+    pub reorder: f32,
+    /// Probability in `[0, 1]` that a packet is delivered twice.
+    pub duplicate: f32,
+}
+
+impl Default for LinkConditions {
+    fn default() -> Self {
+        LinkConditions {
+            latency_ms: 0.0,
+            packet_loss: 0.0,
+            jitter: Jitter::None,
+            reorder: 0.0,
+            duplicate: 0.0,
+        }
+    }
+}
+
+/// Upper bound of the extra delay given to a reordered packet.
+const REORDER_HOLD_MS: f32 = 150.0;
+
+/// Running totals of what happened to the traffic offered to a `LagQueue`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NetworkStats {
+    pub sent: u32,
+    pub dropped: u32,
+    pub duplicated: u32,
+    pub delivered: u32,
+}
+
+/// Length of the rolling window `WindowStats` cover.
+pub const STATS_WINDOW_MS: u128 = 1000;
+
+/// Traffic through a `LagQueue` over the last `STATS_WINDOW_MS`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WindowStats {
+    pub packets: u32,
+    pub messages: u32,
+    pub bytes: usize,
+    pub dropped: u32,
+    /// Average time the delivered packets spent in the queue.
+    pub average_delay_ms: f32,
+}
+
+enum TrafficEvent {
+    Sent { messages: u32, bytes: usize },
+    Dropped,
+    Delivered { delay_ms: u128 },
+}
+
+struct NetworkMessage {
+    send_time: u128,
+    receive_time: u128,
+    /// Order in which the message was queued, so ties on `receive_time` stay first in first out.
+    sequence: u64,
+    payload: Packet,
+}
+
+// `BinaryHeap` is a max-heap, so the ordering is reversed to pop the earliest message first.
+impl Ord for NetworkMessage {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.receive_time, other.sequence).cmp(&(self.receive_time, self.sequence))
+    }
+}
+
+impl PartialOrd for NetworkMessage {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for NetworkMessage {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for NetworkMessage {}
+
+/// One direction of a simulated link. Messages wait here until their delivery time.
+pub struct LagQueue {
+    messages: BinaryHeap<NetworkMessage>,
+    next_sequence: u64,
+    clock: Rc<dyn Clock>,
+    rng: RandGenerator,
+    pub conditions: LinkConditions,
+    pub stats: NetworkStats,
+    /// What happened within the stats window, oldest first.
+    events: VecDeque<(u128, TrafficEvent)>,
+}
+
+impl LagQueue {
+    pub fn new(clock: Rc<dyn Clock>, seed: u64, conditions: LinkConditions) -> Self {
+        let rng = RandGenerator::new();
+        rng.srand(seed);
+
+        LagQueue {
+            messages: BinaryHeap::new(),
+            next_sequence: 0,
+            clock,
+            rng,
+            conditions,
+            stats: NetworkStats::default(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Number of messages queued but not yet received.
+    pub fn in_flight(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Queues `message` for delivery, applying the latency, loss, jitter, reordering and
+    /// duplication of the queue's conditions. Returns `false` if the message was dropped.
+    pub fn send(&mut self, message: Packet) -> bool {
+        self.stats.sent += 1;
+        self.record(TrafficEvent::Sent {
+            messages: (message.reliable.len() + message.unreliable.len()) as u32,
+            bytes: protocol::encode(&message).len(),
+        });
+
+        if self.rng.gen_range(0.0, 1.0) < self.conditions.packet_loss {
+            self.stats.dropped += 1;
+            self.record(TrafficEvent::Dropped);
+            return false;
+        }
+
+        if self.rng.gen_range(0.0, 1.0) < self.conditions.duplicate {
+            self.stats.duplicated += 1;
+            self.enqueue(message.clone());
+        }
+        self.enqueue(message);
+        true
+    }
+
+    fn enqueue(&mut self, message: Packet) {
+        let in_ms = self.clock.now_ms();
+        let conditions = self.conditions;
+
+        //set recv time to time now + latency + jitter
+        let mut delay_ms = (conditions.latency_ms + conditions.jitter.sample(&self.rng)).max(0.0);
+        if self.rng.gen_range(0.0, 1.0) < conditions.reorder {
+            delay_ms += self.rng.gen_range(0.0, REORDER_HOLD_MS);
+        }
+        let receive_time = in_ms + delay_ms as u128;
+
+        // make the NetworkMessage
+        let network_message = NetworkMessage {
+            send_time: in_ms,
+            receive_time,
+            sequence: self.next_sequence,
+            payload: message,
+        };
+        self.next_sequence += 1;
+
+        self.messages.push(network_message);
+    }
+
+    /// Pops the message with the earliest `receive_time`, if it has arrived by now.
+    pub fn receive(&mut self) -> Option<Packet> {
+        let in_ms = self.clock.now_ms();
+
+        if self.messages.peek()?.receive_time > in_ms {
+            return None;
+        }
+
+        let message = self.messages.pop()?;
+        self.stats.delivered += 1;
+        self.record(TrafficEvent::Delivered {
+            delay_ms: in_ms - message.send_time,
+        });
+        Some(message.payload)
+    }
+
+    /// Totals over the last `STATS_WINDOW_MS`. Bytes are counted as encoded for the wire.
+    pub fn window_stats(&self) -> WindowStats {
+        let since = self.clock.now_ms().saturating_sub(STATS_WINDOW_MS);
+        let mut stats = WindowStats::default();
+        let mut delivered = 0;
+        let mut total_delay_ms = 0;
+
+        for (_, event) in self.events.iter().filter(|(time, _)| *time >= since) {
+            match event {
+                TrafficEvent::Sent { messages, bytes } => {
+                    stats.packets += 1;
+                    stats.messages += messages;
+                    stats.bytes += bytes;
+                }
+                TrafficEvent::Dropped => stats.dropped += 1,
+                TrafficEvent::Delivered { delay_ms } => {
+                    delivered += 1;
+                    total_delay_ms += delay_ms;
+                }
+            }
+        }
+        if delivered > 0 {
+            stats.average_delay_ms = total_delay_ms as f32 / delivered as f32;
+        }
+        stats
+    }
+
+    fn record(&mut self, event: TrafficEvent) {
+        let now = self.clock.now_ms();
+        while self
+            .events
+            .front()
+            .is_some_and(|(time, _)| *time + STATS_WINDOW_MS < now)
+        {
+            self.events.pop_front();
+        }
+        self.events.push_back((now, event));
+    }
+}
+
+/// One end of an in-process simulated link. It sends into the queue the other end receives
+/// from, and the other way round.
+#[derive(Clone)]
+pub struct LagNetwork {
+    pub outbound: Rc<RefCell<LagQueue>>,
+    pub inbound: Rc<RefCell<LagQueue>>,
+}
+
+impl LagNetwork {
+    /// Creates both ends of a link whose two directions start out with the same `conditions`.
+    pub fn pair(
+        clock: Rc<dyn Clock>,
+        seed: u64,
+        conditions: LinkConditions,
+    ) -> (LagNetwork, LagNetwork) {
+        // seed the directions differently so they don't drop and delay in lockstep
+        let a_to_b = Rc::new(RefCell::new(LagQueue::new(
+            clock.clone(),
+            seed.wrapping_mul(2),
+            conditions,
+        )));
+        let b_to_a = Rc::new(RefCell::new(LagQueue::new(
+            clock,
+            seed.wrapping_mul(2) + 1,
+            conditions,
+        )));
+
+        (
+            LagNetwork {
+                outbound: a_to_b.clone(),
+                inbound: b_to_a.clone(),
+            },
+            LagNetwork {
+                outbound: b_to_a,
+                inbound: a_to_b,
+            },
+        )
+    }
+}
+
+impl Transport for LagNetwork {
+    fn send(&mut self, packet: Packet) {
+        self.outbound.borrow_mut().send(packet);
+    }
+
+    fn receive(&mut self) -> Option<Packet> {
+        self.inbound.borrow_mut().receive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::drain;
+
+    fn queue(clock: &VirtualClock) -> LagQueue {
+        LagQueue::new(Rc::new(clock.clone()), 1, LinkConditions::default())
+    }
+
+    /// Sequences of the packets the queue delivers now.
+    fn delivered(queue: &mut LagQueue) -> Vec<u16> {
+        drain(|| queue.receive())
+            .into_iter()
+            .map(|packet| packet.sequence)
+            .collect()
+    }
+
+    #[test]
+    fn holds_messages_until_their_receive_time() {
+        let clock = VirtualClock::new();
+        let mut queue = queue(&clock);
+        queue.conditions.latency_ms = 100.0;
+        queue.send(Packet::empty(0));
+
+        clock.advance(0.099);
+        assert!(queue.receive().is_none());
+        clock.advance(0.001);
+        assert_eq!(delivered(&mut queue), [0]);
+    }
+
+    #[test]
+    fn delivers_in_receive_time_order() {
+        let clock = VirtualClock::new();
+        let mut queue = queue(&clock);
+        for (sequence, latency_ms) in [(0, 300.0), (1, 100.0), (2, 200.0)] {
+            queue.conditions.latency_ms = latency_ms;
+            queue.send(Packet::empty(sequence));
+        }
+
+        clock.advance(0.15);
+        assert_eq!(delivered(&mut queue), [1]);
+        clock.advance(1.0);
+        assert_eq!(delivered(&mut queue), [2, 0]);
+    }
+
+    #[test]
+    fn equal_receive_times_stay_first_in_first_out() {
+        let clock = VirtualClock::new();
+        let mut queue = queue(&clock);
+        queue.conditions.latency_ms = 50.0;
+        for sequence in 0..20 {
+            queue.send(Packet::empty(sequence));
+        }
+        // sent 50 ms later with no latency, so due at the same time as the others
+        clock.advance(0.05);
+        queue.conditions.latency_ms = 0.0;
+        queue.send(Packet::empty(20));
+
+        assert_eq!(delivered(&mut queue), (0..=20).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,764 @@
+//! Binary encoding of `Packet`s, shared by every transport that leaves the process.
+//!
+//! Every datagram starts with the protocol version and the packet header: its sequence, the
+//! ack (plus one, so zero means nothing received yet) and the 32-bit ack bitfield. Then come
+//! the reliable messages, each prefixed with its message id, and the unreliable messages, both
+//! as a count followed by a message tag and body per message. Integers are LEB128 varints so
+//! small ids and sequence numbers take a single byte, except for bitfields and random values,
+//! which are little-endian `u32`s. Floats are little-endian IEEE 754. Snapshot positions may
+//! instead be sent as fixed-point integers, see `Quantization`.
+
+use crate::{world_state, InputBufferStatus, Message, MovementInput, Packet, WorldStateMessage};
+use std::error::Error;
+use std::fmt;
+
+/// Bumped whenever the encoding changes, so mismatched builds reject each other's packets.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+const TAG_MOVEMENT: u8 = 0;
+const TAG_WORLD_STATE: u8 = 1;
+const TAG_CONNECT_REQUEST: u8 = 2;
+const TAG_ACCEPTED: u8 = 3;
+const TAG_PING: u8 = 4;
+const TAG_PONG: u8 = 5;
+const TAG_CHALLENGE: u8 = 6;
+const TAG_CHALLENGE_RESPONSE: u8 = 7;
+const TAG_DENIED: u8 = 8;
+const TAG_KEEP_ALIVE: u8 = 9;
+const TAG_DISCONNECT: u8 = 10;
+const TAG_INPUT_BUFFER: u8 = 11;
+const TAG_SNAPSHOT_NACK: u8 = 12;
+
+/// Set in a snapshot's position format when quantized positions go on the wire as floats.
+const FLOAT_POSITIONS: u8 = 0x80;
+
+/// Highest number of fractional bits a quantized position can have.
+pub const MAX_PRECISION_BITS: u8 = 16;
+
+/// Fixed-point encoding for snapshot positions. Positions are rounded to a multiple of
+/// `1 / 2^precision_bits`. An `f32` only has 24 bits of mantissa, so that step holds while
+/// `|position| * 2^precision_bits < 2^24`; further out positions keep whatever coarser step the
+/// `f32` has there. Either way scaling by a power of two is exact, so both ends see
+/// bit-identical values.
+///
+/// `[min, max]` is the range the fixed-point integers cover. It never moves an entity: a
+/// snapshot with a position outside it sends its positions as floats instead, on the same step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quantization {
+    pub min: f32,
+    pub max: f32,
+    pub precision_bits: u8,
+}
+
+impl Quantization {
+    /// Snaps `position` onto the step. It is not clamped to the range.
+    pub fn apply(&self, position: f32) -> f32 {
+        quantize(position, self.precision_bits)
+    }
+
+    /// Whether `position` can be sent as a fixed-point integer.
+    pub fn contains(&self, position: f32) -> bool {
+        (self.min..=self.max).contains(&position)
+    }
+}
+
+/// Rounds `position` to a multiple of `1 / 2^precision_bits`, or to the nearest `f32` where
+/// those are further apart, without clamping it. Clients predict with this so their positions
+/// match the quantized ones the server simulates from.
+pub fn quantize(position: f32, precision_bits: u8) -> f32 {
+    let scale = (1u32 << precision_bits) as f32;
+    (position * scale).round() / scale
+}
+
+impl Default for Quantization {
+    fn default() -> Self {
+        Quantization {
+            min: -4096.0,
+            max: 4096.0,
+            precision_bits: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The packet was written by a different protocol version.
+    UnsupportedVersion(u8),
+    UnknownMessage(u8),
+    /// The packet ended in the middle of a field.
+    Truncated,
+    /// A varint ran past the width of its type.
+    InvalidVarint,
+    /// A quantized position claimed more than `MAX_PRECISION_BITS` fractional bits.
+    InvalidPrecision(u8),
+    /// A movement input went some other way than -1, 0 or 1.
+    InvalidDirection(i32),
+    /// Bytes were left over after a complete message.
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported protocol version {} (expected {})",
+                version, PROTOCOL_VERSION
+            ),
+            DecodeError::UnknownMessage(tag) => write!(f, "unknown message tag {}", tag),
+            DecodeError::Truncated => write!(f, "packet is truncated"),
+            DecodeError::InvalidVarint => write!(f, "varint is too long"),
+            DecodeError::InvalidPrecision(bits) => {
+                write!(f, "position precision of {} bits is out of range", bits)
+            }
+            DecodeError::InvalidDirection(direction) => {
+                write!(f, "movement direction {} is out of range", direction)
+            }
+            DecodeError::TrailingBytes(count) => {
+                write!(f, "{} unexpected bytes after the message", count)
+            }
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn varint(&mut self, mut value: u32) {
+        while value >= 0x80 {
+            self.bytes.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+
+    /// Signed varint with zigzag encoding, so small negative numbers stay small.
+    fn signed_varint(&mut self, value: i32) {
+        self.varint(((value << 1) ^ (value >> 31)) as u32);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        let (&value, rest) = self.bytes.split_first().ok_or(DecodeError::Truncated)?;
+        self.bytes = rest;
+        Ok(value)
+    }
+
+    fn varint(&mut self) -> Result<u32, DecodeError> {
+        let mut value = 0u32;
+        for shift in (0..35).step_by(7) {
+            let byte = self.u8()?;
+            if shift == 28 && byte > 0x0f {
+                return Err(DecodeError::InvalidVarint);
+            }
+            value |= ((byte & 0x7f) as u32) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::InvalidVarint)
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        u16::try_from(self.varint()?).map_err(|_| DecodeError::InvalidVarint)
+    }
+
+    fn signed_varint(&mut self) -> Result<i32, DecodeError> {
+        let value = self.varint()?;
+        Ok((value >> 1) as i32 ^ -((value & 1) as i32))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        let (value, rest) = self
+            .bytes
+            .split_first_chunk::<4>()
+            .ok_or(DecodeError::Truncated)?;
+        self.bytes = rest;
+        Ok(u32::from_le_bytes(*value))
+    }
+
+    fn f32(&mut self) -> Result<f32, DecodeError> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+}
+
+pub fn encode(packet: &Packet) -> Vec<u8> {
+    let mut w = Writer { bytes: Vec::new() };
+    w.u8(PROTOCOL_VERSION);
+    w.varint(packet.sequence as u32);
+    w.varint(packet.ack.map_or(0, |ack| ack as u32 + 1));
+    w.u32(packet.ack_bits);
+
+    w.varint(packet.reliable.len() as u32);
+    for (id, message) in &packet.reliable {
+        w.varint(*id as u32);
+        write_message(&mut w, message);
+    }
+    w.varint(packet.unreliable.len() as u32);
+    for message in &packet.unreliable {
+        write_message(&mut w, message);
+    }
+
+    w.bytes
+}
+
+/// Bytes `message` takes up inside a packet.
+pub fn encoded_size(message: &Message) -> usize {
+    let mut w = Writer { bytes: Vec::new() };
+    write_message(&mut w, message);
+    w.bytes.len()
+}
+
+fn write_message(w: &mut Writer, message: &Message) {
+    match message {
+        Message::Movement(input) => {
+            w.u8(TAG_MOVEMENT);
+            w.varint(input.tick);
+            w.signed_varint(input.direction as i32);
+            w.varint(input.entity_id);
+            w.varint(input.input_sequence_number);
+        }
+        Message::WorldState(world_state) => {
+            w.u8(TAG_WORLD_STATE);
+            w.varint(world_state.sequence);
+            w.varint(world_state.tick);
+            w.varint(world_state.time_ms);
+            // baseline as a distance back from this snapshot, 0 for a full snapshot
+            w.varint(
+                world_state
+                    .baseline
+                    .map_or(0, |b| world_state.sequence.wrapping_sub(b)),
+            );
+            w.varint(world_state.removed.len() as u32);
+            for entity_id in &world_state.removed {
+                w.varint(*entity_id);
+            }
+            w.varint(world_state.world_state.len() as u32);
+            if world_state.world_state.is_empty() {
+                return;
+            }
+
+            // position format: 0 for raw floats, otherwise fractional bits + 1, flagged if they
+            // are still sent as floats
+            let float_flag = if world_state.float_positions {
+                FLOAT_POSITIONS
+            } else {
+                0
+            };
+            w.u8(world_state
+                .position_bits
+                .map_or(0, |bits| (bits + 1) | float_flag));
+            let fixed_point_bits = world_state
+                .position_bits
+                .filter(|_| !world_state.float_positions);
+            for state in &world_state.world_state {
+                w.varint(state.entity_id);
+                match fixed_point_bits {
+                    None => w.f32(state.position),
+                    Some(bits) => {
+                        w.signed_varint((state.position * (1u32 << bits) as f32).round() as i32)
+                    }
+                }
+                w.varint(state.last_processed_input as u32);
+            }
+        }
+        Message::ConnectRequest => w.u8(TAG_CONNECT_REQUEST),
+        Message::Challenge { salt } => {
+            w.u8(TAG_CHALLENGE);
+            w.u32(*salt);
+        }
+        Message::ChallengeResponse { salt } => {
+            w.u8(TAG_CHALLENGE_RESPONSE);
+            w.u32(*salt);
+        }
+        Message::Denied => w.u8(TAG_DENIED),
+        Message::KeepAlive => w.u8(TAG_KEEP_ALIVE),
+        Message::Disconnect => w.u8(TAG_DISCONNECT),
+        Message::Accepted {
+            entity_id,
+            tick,
+            tick_interval,
+        } => {
+            w.u8(TAG_ACCEPTED);
+            w.varint(*entity_id);
+            w.varint(*tick);
+            w.f32(*tick_interval);
+        }
+        Message::Ping { id } => {
+            w.u8(TAG_PING);
+            w.varint(*id);
+        }
+        Message::Pong { id } => {
+            w.u8(TAG_PONG);
+            w.varint(*id);
+        }
+        Message::InputBuffer(status) => {
+            w.u8(TAG_INPUT_BUFFER);
+            w.signed_varint(status.depth);
+            w.varint(status.target_depth);
+            w.varint(status.underruns);
+            w.varint(status.overruns);
+            w.varint(status.late);
+        }
+        Message::SnapshotNack { sequence } => {
+            w.u8(TAG_SNAPSHOT_NACK);
+            w.varint(*sequence);
+        }
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Packet, DecodeError> {
+    let mut r = Reader { bytes };
+
+    let version = r.u8()?;
+    if version != PROTOCOL_VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+
+    let sequence = r.u16()?;
+    let ack = match r.varint()? {
+        0 => None,
+        ack => Some(u16::try_from(ack - 1).map_err(|_| DecodeError::InvalidVarint)?),
+    };
+    let ack_bits = r.u32()?;
+
+    // don't trust the counts for the allocations, every message takes at least a byte
+    let count = r.varint()? as usize;
+    let mut reliable = Vec::with_capacity(count.min(r.bytes.len()));
+    for _ in 0..count {
+        let id = r.u16()?;
+        reliable.push((id, read_message(&mut r)?));
+    }
+    let count = r.varint()? as usize;
+    let mut unreliable = Vec::with_capacity(count.min(r.bytes.len()));
+    for _ in 0..count {
+        unreliable.push(read_message(&mut r)?);
+    }
+
+    if !r.bytes.is_empty() {
+        return Err(DecodeError::TrailingBytes(r.bytes.len()));
+    }
+    Ok(Packet {
+        sequence,
+        ack,
+        ack_bits,
+        reliable,
+        unreliable,
+    })
+}
+
+fn read_message(r: &mut Reader) -> Result<Message, DecodeError> {
+    let message = match r.u8()? {
+        TAG_MOVEMENT => {
+            let tick = r.varint()?;
+            let direction = match r.signed_varint()? {
+                direction @ -1..=1 => direction as i8,
+                direction => return Err(DecodeError::InvalidDirection(direction)),
+            };
+            Message::Movement(MovementInput {
+                tick,
+                direction,
+                entity_id: r.varint()?,
+                input_sequence_number: r.varint()?,
+            })
+        }
+        TAG_WORLD_STATE => {
+            let sequence = r.varint()?;
+            let tick = r.varint()?;
+            let time_ms = r.varint()?;
+            let baseline = match r.varint()? {
+                0 => None,
+                distance => Some(sequence.wrapping_sub(distance)),
+            };
+            let count = r.varint()? as usize;
+            let mut removed = Vec::with_capacity(count.min(r.bytes.len()));
+            for _ in 0..count {
+                removed.push(r.varint()?);
+            }
+            let count = r.varint()?;
+            let format = match count {
+                0 => 0,
+                _ => r.u8()?,
+            };
+            let float_positions = format & FLOAT_POSITIONS != 0;
+            let position_bits = match format & !FLOAT_POSITIONS {
+                0 => None,
+                bits if bits - 1 > MAX_PRECISION_BITS => {
+                    return Err(DecodeError::InvalidPrecision(bits - 1))
+                }
+                bits => Some(bits - 1),
+            };
+            let fixed_point_bits = position_bits.filter(|_| !float_positions);
+            // each entry takes at least 3 bytes
+            let mut states = Vec::with_capacity((count as usize).min(r.bytes.len() / 3));
+            for _ in 0..count {
+                let entity_id = r.varint()?;
+                let position = match fixed_point_bits {
+                    None => r.f32()?,
+                    Some(bits) => r.signed_varint()? as f32 / (1u32 << bits) as f32,
+                };
+                states.push(world_state {
+                    entity_id,
+                    position,
+                    last_processed_input: r.varint()? as f32,
+                });
+            }
+            Message::WorldState(WorldStateMessage {
+                sequence,
+                tick,
+                time_ms,
+                baseline,
+                position_bits,
+                float_positions,
+                removed,
+                world_state: states,
+            })
+        }
+        TAG_CONNECT_REQUEST => Message::ConnectRequest,
+        TAG_CHALLENGE => Message::Challenge { salt: r.u32()? },
+        TAG_CHALLENGE_RESPONSE => Message::ChallengeResponse { salt: r.u32()? },
+        TAG_DENIED => Message::Denied,
+        TAG_KEEP_ALIVE => Message::KeepAlive,
+        TAG_DISCONNECT => Message::Disconnect,
+        TAG_ACCEPTED => Message::Accepted {
+            entity_id: r.varint()?,
+            tick: r.varint()?,
+            tick_interval: r.f32()?,
+        },
+        TAG_PING => Message::Ping { id: r.varint()? },
+        TAG_PONG => Message::Pong { id: r.varint()? },
+        TAG_INPUT_BUFFER => Message::InputBuffer(InputBufferStatus {
+            depth: r.signed_varint()?,
+            target_depth: r.varint()?,
+            underruns: r.varint()?,
+            overruns: r.varint()?,
+            late: r.varint()?,
+        }),
+        TAG_SNAPSHOT_NACK => Message::SnapshotNack {
+            sequence: r.varint()?,
+        },
+        tag => return Err(DecodeError::UnknownMessage(tag)),
+    };
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InputBufferStatus;
+
+    fn every_message() -> Vec<Message> {
+        vec![
+            Message::Movement(MovementInput {
+                tick: 70_000,
+                direction: -1,
+                entity_id: 3,
+                input_sequence_number: u32::MAX,
+            }),
+            Message::WorldState(WorldStateMessage {
+                sequence: 1000,
+                tick: 500,
+                time_ms: 10_000,
+                baseline: Some(990),
+                position_bits: None,
+                float_positions: false,
+                removed: vec![4, 300],
+                world_state: vec![world_state {
+                    entity_id: 1,
+                    position: 123.456,
+                    last_processed_input: 42.0,
+                }],
+            }),
+            Message::WorldState(WorldStateMessage {
+                sequence: 7,
+                tick: 7,
+                time_ms: 700,
+                baseline: None,
+                position_bits: Some(4),
+                float_positions: false,
+                removed: Vec::new(),
+                world_state: vec![
+                    world_state {
+                        entity_id: 1,
+                        position: -40.5625,
+                        last_processed_input: 0.0,
+                    },
+                    world_state {
+                        entity_id: 2,
+                        position: 4096.0,
+                        last_processed_input: 9.0,
+                    },
+                ],
+            }),
+            // quantized, but outside the fixed-point range
+            Message::WorldState(WorldStateMessage {
+                sequence: 9,
+                tick: 9,
+                time_ms: 900,
+                baseline: None,
+                position_bits: Some(4),
+                float_positions: true,
+                removed: Vec::new(),
+                world_state: vec![world_state {
+                    entity_id: 1,
+                    position: 5000.0625,
+                    last_processed_input: 3.0,
+                }],
+            }),
+            // no entries, so no position format either
+            Message::WorldState(WorldStateMessage {
+                sequence: 8,
+                tick: 8,
+                time_ms: 800,
+                baseline: Some(7),
+                position_bits: None,
+                float_positions: false,
+                removed: vec![2],
+                world_state: Vec::new(),
+            }),
+            Message::ConnectRequest,
+            Message::Challenge { salt: 0xdead_beef },
+            Message::ChallengeResponse { salt: u32::MAX },
+            Message::Accepted {
+                entity_id: 2,
+                tick: 12_345,
+                tick_interval: 0.02,
+            },
+            Message::Denied,
+            Message::KeepAlive,
+            Message::Disconnect,
+            Message::Ping { id: 0 },
+            Message::Pong { id: 128 },
+            Message::InputBuffer(InputBufferStatus {
+                depth: -3,
+                target_depth: 2,
+                underruns: 17,
+                overruns: 0,
+                late: 5,
+            }),
+            Message::SnapshotNack { sequence: 70_000 },
+        ]
+    }
+
+    fn packet_of(messages: Vec<Message>) -> Packet {
+        Packet {
+            sequence: u16::MAX,
+            ack: Some(u16::MAX),
+            ack_bits: 0x8000_0001,
+            reliable: messages
+                .iter()
+                .cloned()
+                .enumerate()
+                .map(|(id, message)| (id as u16, message))
+                .collect(),
+            unreliable: messages,
+        }
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        for message in every_message() {
+            let packet = packet_of(vec![message]);
+            assert_eq!(decode(&encode(&packet)), Ok(packet));
+        }
+    }
+
+    #[test]
+    fn packet_header_round_trips() {
+        for (sequence, ack) in [(0, None), (1, Some(0)), (u16::MAX, Some(u16::MAX))] {
+            let packet = Packet {
+                sequence,
+                ack,
+                ack_bits: 0,
+                reliable: Vec::new(),
+                unreliable: Vec::new(),
+            };
+            assert_eq!(decode(&encode(&packet)), Ok(packet));
+        }
+    }
+
+    #[test]
+    fn encoded_size_matches_encoding() {
+        for message in every_message() {
+            let empty = encode(&packet_of(Vec::new())).len();
+            let single = Packet {
+                unreliable: vec![message.clone()],
+                ..packet_of(Vec::new())
+            };
+            assert_eq!(encoded_size(&message), encode(&single).len() - empty);
+        }
+    }
+
+    #[test]
+    fn varints_round_trip_at_their_limits() {
+        let mut w = Writer { bytes: Vec::new() };
+        let unsigned = [0, 1, 127, 128, 16_383, 16_384, u32::MAX];
+        let signed = [0, -1, 1, -64, 64, i32::MIN, i32::MAX];
+        for value in unsigned {
+            w.varint(value);
+        }
+        for value in signed {
+            w.signed_varint(value);
+        }
+
+        let mut r = Reader { bytes: &w.bytes };
+        for value in unsigned {
+            assert_eq!(r.varint(), Ok(value));
+        }
+        for value in signed {
+            assert_eq!(r.signed_varint(), Ok(value));
+        }
+        assert!(r.bytes.is_empty());
+    }
+
+    #[test]
+    fn small_varints_take_one_byte() {
+        let mut w = Writer { bytes: Vec::new() };
+        w.varint(127);
+        w.signed_varint(-64);
+        assert_eq!(w.bytes.len(), 2);
+    }
+
+    #[test]
+    fn quantized_positions_round_trip_beyond_the_f32_step() {
+        let quantization = Quantization {
+            precision_bits: MAX_PRECISION_BITS,
+            ..Quantization::default()
+        };
+        let world_state = [0.1, -1.337, 300.7, 4095.123, -4096.0]
+            .into_iter()
+            .enumerate()
+            .map(|(id, position)| world_state {
+                entity_id: id as u32,
+                position: quantization.apply(position),
+                last_processed_input: 0.0,
+            })
+            .collect();
+        let packet = packet_of(vec![Message::WorldState(WorldStateMessage {
+            sequence: 1,
+            tick: 1,
+            time_ms: 0,
+            baseline: None,
+            position_bits: Some(quantization.precision_bits),
+            float_positions: false,
+            removed: Vec::new(),
+            world_state,
+        })]);
+        assert_eq!(decode(&encode(&packet)), Ok(packet));
+    }
+
+    #[test]
+    fn rejects_overlong_varints() {
+        let mut r = Reader {
+            bytes: &[0xff, 0xff, 0xff, 0xff, 0x1f],
+        };
+        assert_eq!(r.varint(), Err(DecodeError::InvalidVarint));
+
+        let mut r = Reader {
+            bytes: &[0x80, 0x80, 0x80, 0x80, 0x80, 0x00],
+        };
+        assert_eq!(r.varint(), Err(DecodeError::InvalidVarint));
+    }
+
+    #[test]
+    fn rejects_truncated_packets() {
+        let bytes = encode(&packet_of(every_message()));
+        for len in 0..bytes.len() {
+            assert_eq!(
+                decode(&bytes[..len]),
+                Err(DecodeError::Truncated),
+                "{} of {} bytes",
+                len,
+                bytes.len()
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_other_protocol_versions() {
+        let mut bytes = encode(&packet_of(vec![Message::KeepAlive]));
+        bytes[0] = PROTOCOL_VERSION.wrapping_add(1);
+        assert_eq!(
+            decode(&bytes),
+            Err(DecodeError::UnsupportedVersion(
+                PROTOCOL_VERSION.wrapping_add(1)
+            ))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_message_tags() {
+        let packet = Packet {
+            unreliable: vec![Message::KeepAlive],
+            ..packet_of(Vec::new())
+        };
+        let mut bytes = encode(&packet);
+        // the keep alive is only its tag, at the very end
+        *bytes.last_mut().unwrap() = 200;
+        assert_eq!(decode(&bytes), Err(DecodeError::UnknownMessage(200)));
+    }
+
+    #[test]
+    fn rejects_out_of_range_precision() {
+        let packet = packet_of(vec![Message::WorldState(WorldStateMessage {
+            sequence: 1,
+            tick: 1,
+            time_ms: 0,
+            baseline: None,
+            position_bits: Some(MAX_PRECISION_BITS + 1),
+            float_positions: false,
+            removed: Vec::new(),
+            world_state: vec![world_state {
+                entity_id: 1,
+                position: 1.0,
+                last_processed_input: 0.0,
+            }],
+        })]);
+        assert_eq!(
+            decode(&encode(&packet)),
+            Err(DecodeError::InvalidPrecision(MAX_PRECISION_BITS + 1))
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_directions() {
+        let packet = packet_of(vec![Message::Movement(MovementInput {
+            tick: 1,
+            direction: 2,
+            entity_id: 1,
+            input_sequence_number: 0,
+        })]);
+        assert_eq!(
+            decode(&encode(&packet)),
+            Err(DecodeError::InvalidDirection(2))
+        );
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut bytes = encode(&packet_of(vec![Message::Denied]));
+        bytes.extend_from_slice(&[0, 0]);
+        assert_eq!(decode(&bytes), Err(DecodeError::TrailingBytes(2)));
+    }
+}
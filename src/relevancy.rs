@@ -0,0 +1,43 @@
+//! Interest management: which entities each client is told about.
+//!
+//! The server asks a client's `RelevancyFilter` about every entity before putting it in that
+//! client's snapshot. Entities that stop being relevant are listed as removed in the next delta,
+//! so the client drops them as if they had left, and are sent again once relevant.
+
+use crate::Entity;
+
+/// Default `DistanceFilter` radius, a bit over half the width of the demo window.
+pub const DEFAULT_RELEVANCY_RADIUS: f32 = 400.0;
+
+/// Decides which entities go into a client's snapshots. A client's own entity is always sent.
+pub trait RelevancyFilter {
+    fn is_relevant(&self, viewer: &Entity, entity: &Entity) -> bool;
+
+    /// How far the filter reaches, for editing from the UI, if it works by distance.
+    fn radius_mut(&mut self) -> Option<&mut f32> {
+        None
+    }
+}
+
+/// Sends the entities within `radius` of the client's own.
+pub struct DistanceFilter {
+    pub radius: f32,
+}
+
+impl Default for DistanceFilter {
+    fn default() -> Self {
+        DistanceFilter {
+            radius: DEFAULT_RELEVANCY_RADIUS,
+        }
+    }
+}
+
+impl RelevancyFilter for DistanceFilter {
+    fn is_relevant(&self, viewer: &Entity, entity: &Entity) -> bool {
+        (entity.x - viewer.x).abs() <= self.radius
+    }
+
+    fn radius_mut(&mut self) -> Option<&mut f32> {
+        Some(&mut self.radius)
+    }
+}
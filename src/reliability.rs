@@ -0,0 +1,420 @@
+//! Acknowledgements and message channels on top of an unreliable `Transport`.
+//!
+//! Every packet carries its own sequence number, the newest sequence received from the other
+//! end and a bitfield for the 32 packets before that one. As long as packets flow both ways,
+//! each end learns which of its packets arrived without sending anything extra. Reliable
+//! messages ride along in every packet until one carrying them is acknowledged, and are handed
+//! over in the order they were sent. Unreliable messages are sent once and handed over as they
+//! arrive.
+
+use crate::transport::Transport;
+use crate::{Message, Packet};
+use std::collections::{HashMap, VecDeque};
+
+/// Sent packets remembered for matching acks. An ack reaches at most 33 packets back.
+const SENT_PACKET_HISTORY: usize = 64;
+
+/// Most reliable messages repeated in one packet, so a backlog can't outgrow a datagram.
+const MAX_RELIABLE_PER_PACKET: usize = 16;
+
+/// Whether sequence `a` comes after `b`, allowing for wrap around.
+pub fn sequence_greater_than(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) > 0
+}
+
+struct SentPacket {
+    sequence: u16,
+    acked: bool,
+    /// Reliable messages the packet carried, delivered once it is acked.
+    reliable_ids: Vec<u16>,
+}
+
+/// One end of a connection, sending and receiving `Message`s over a `Transport` in packets.
+pub struct Endpoint {
+    transport: Box<dyn Transport>,
+    local_sequence: u16,
+    /// Newest packet received, and which of the 32 before it arrived too.
+    remote_sequence: Option<u16>,
+    received_bits: u32,
+    sent_packets: VecDeque<SentPacket>,
+    newest_acked: Option<u16>,
+    newly_acked: VecDeque<u16>,
+    next_reliable_id: u16,
+    unacked_reliable: VecDeque<(u16, Message)>,
+    /// Next reliable message to hand over, and those that arrived ahead of it.
+    expected_reliable_id: u16,
+    early_reliable: HashMap<u16, Message>,
+    unreliable: Vec<Message>,
+    inbox: VecDeque<Message>,
+}
+
+impl Endpoint {
+    pub fn new(transport: Box<dyn Transport>) -> Self {
+        Endpoint {
+            transport,
+            local_sequence: 0,
+            remote_sequence: None,
+            received_bits: 0,
+            sent_packets: VecDeque::new(),
+            newest_acked: None,
+            newly_acked: VecDeque::new(),
+            next_reliable_id: 0,
+            unacked_reliable: VecDeque::new(),
+            expected_reliable_id: 0,
+            early_reliable: HashMap::new(),
+            unreliable: Vec::new(),
+            inbox: VecDeque::new(),
+        }
+    }
+
+    /// Queues `message` to be repeated in every packet until the other end acknowledges it.
+    pub fn send_reliable(&mut self, message: Message) {
+        self.unacked_reliable
+            .push_back((self.next_reliable_id, message));
+        self.next_reliable_id = self.next_reliable_id.wrapping_add(1);
+    }
+
+    /// Queues `message` for the next packet only.
+    pub fn send_unreliable(&mut self, message: Message) {
+        self.unreliable.push(message);
+    }
+
+    /// Reliable messages sent but not acknowledged yet.
+    pub fn pending_reliable(&self) -> usize {
+        self.unacked_reliable.len()
+    }
+
+    /// Whether `flush` has anything to send besides acks.
+    pub fn has_queued(&self) -> bool {
+        !self.unreliable.is_empty() || !self.unacked_reliable.is_empty()
+    }
+
+    /// Fraction of the remembered packets that never got acked. Packets sent after the
+    /// newest acked one may still be on their way and don't count.
+    pub fn packet_loss(&self) -> f32 {
+        let Some(newest) = self.newest_acked else {
+            return 0.0;
+        };
+        let settled = self
+            .sent_packets
+            .iter()
+            .filter(|sent| !sequence_greater_than(sent.sequence, newest));
+        let (count, lost) = settled.fold((0, 0), |(count, lost), sent| {
+            (count + 1, lost + !sent.acked as u32)
+        });
+        if count == 0 {
+            0.0
+        } else {
+            lost as f32 / count as f32
+        }
+    }
+
+    /// Sends a packet with everything queued and the acks for what has been received. Returns
+    /// its sequence, to look for in `take_acked` later.
+    pub fn flush(&mut self) -> u16 {
+        let sequence = self.local_sequence;
+        self.local_sequence = self.local_sequence.wrapping_add(1);
+
+        let reliable: Vec<(u16, Message)> = self
+            .unacked_reliable
+            .iter()
+            .take(MAX_RELIABLE_PER_PACKET)
+            .cloned()
+            .collect();
+
+        self.sent_packets.push_back(SentPacket {
+            sequence,
+            acked: false,
+            reliable_ids: reliable.iter().map(|(id, _)| *id).collect(),
+        });
+        if self.sent_packets.len() > SENT_PACKET_HISTORY {
+            self.sent_packets.pop_front();
+        }
+
+        self.transport.send(Packet {
+            sequence,
+            ack: self.remote_sequence,
+            ack_bits: self.received_bits,
+            reliable,
+            unreliable: std::mem::take(&mut self.unreliable),
+        });
+
+        sequence
+    }
+
+    /// Returns the next message from the other end, reliable ones in the order they were sent.
+    pub fn receive(&mut self) -> Option<Message> {
+        while let Some(packet) = self.transport.receive() {
+            self.process(packet);
+        }
+        self.inbox.pop_front()
+    }
+
+    /// Sequences of sent packets acknowledged since the last call, oldest first. Only the
+    /// latest `SENT_PACKET_HISTORY` are kept if nobody asks.
+    pub fn take_acked(&mut self) -> Vec<u16> {
+        self.newly_acked.drain(..).collect()
+    }
+
+    fn process(&mut self, packet: Packet) {
+        // note the packet for our acks, dropping duplicates and anything too old to ack
+        match self.remote_sequence {
+            Some(remote) if sequence_greater_than(packet.sequence, remote) => {
+                let shift = packet.sequence.wrapping_sub(remote) as u32;
+                self.received_bits = self.received_bits.checked_shl(shift).unwrap_or(0)
+                    | 1u32.checked_shl(shift - 1).unwrap_or(0);
+                self.remote_sequence = Some(packet.sequence);
+            }
+            Some(remote) => {
+                let distance = remote.wrapping_sub(packet.sequence) as u32;
+                if distance == 0 || distance > 32 {
+                    return;
+                }
+                let bit = 1 << (distance - 1);
+                if self.received_bits & bit != 0 {
+                    return;
+                }
+                self.received_bits |= bit;
+            }
+            None => self.remote_sequence = Some(packet.sequence),
+        }
+
+        if let Some(ack) = packet.ack {
+            self.acknowledge(ack);
+            for n in 0..32 {
+                if packet.ack_bits & (1 << n) != 0 {
+                    self.acknowledge(ack.wrapping_sub(n + 1));
+                }
+            }
+        }
+
+        for (id, message) in packet.reliable {
+            // repeats of messages already handed over
+            if sequence_greater_than(self.expected_reliable_id, id) {
+                continue;
+            }
+            self.early_reliable.insert(id, message);
+        }
+        while let Some(message) = self.early_reliable.remove(&self.expected_reliable_id) {
+            self.inbox.push_back(message);
+            self.expected_reliable_id = self.expected_reliable_id.wrapping_add(1);
+        }
+
+        self.inbox.extend(packet.unreliable);
+    }
+
+    fn acknowledge(&mut self, sequence: u16) {
+        let Some(sent) = self
+            .sent_packets
+            .iter_mut()
+            .find(|sent| sent.sequence == sequence && !sent.acked)
+        else {
+            return;
+        };
+        sent.acked = true;
+        if self
+            .newest_acked
+            .is_none_or(|newest| sequence_greater_than(sequence, newest))
+        {
+            self.newest_acked = Some(sequence);
+        }
+
+        self.unacked_reliable
+            .retain(|(id, _)| !sent.reliable_ids.contains(id));
+        self.newly_acked.push_back(sequence);
+        if self.newly_acked.len() > SENT_PACKET_HISTORY {
+            self.newly_acked.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drain;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Packets each way, for the test to deliver, drop or reorder by hand.
+    #[derive(Clone, Default)]
+    struct Wire {
+        sent: Rc<RefCell<VecDeque<Packet>>>,
+        incoming: Rc<RefCell<VecDeque<Packet>>>,
+    }
+
+    impl Transport for Wire {
+        fn send(&mut self, packet: Packet) {
+            self.sent.borrow_mut().push_back(packet);
+        }
+
+        fn receive(&mut self) -> Option<Packet> {
+            self.incoming.borrow_mut().pop_front()
+        }
+    }
+
+    fn endpoint() -> (Endpoint, Wire) {
+        let wire = Wire::default();
+        (Endpoint::new(Box::new(wire.clone())), wire)
+    }
+
+    /// Hands an empty packet with `sequence` to the endpoint behind `wire` and returns the ack
+    /// header it answers with.
+    fn ack_after(endpoint: &mut Endpoint, wire: &Wire, sequence: u16) -> (Option<u16>, u32) {
+        wire.incoming
+            .borrow_mut()
+            .push_back(Packet::empty(sequence));
+        while endpoint.receive().is_some() {}
+        endpoint.flush();
+        let sent = wire.sent.borrow_mut().pop_back().unwrap();
+        (sent.ack, sent.ack_bits)
+    }
+
+    fn take_sent(wire: &Wire) -> Vec<Packet> {
+        wire.sent.borrow_mut().drain(..).collect()
+    }
+
+    #[test]
+    fn compares_sequences_across_wrap_around() {
+        assert!(sequence_greater_than(1, 0));
+        assert!(!sequence_greater_than(0, 1));
+        assert!(!sequence_greater_than(5, 5));
+        assert!(sequence_greater_than(0, u16::MAX));
+        assert!(sequence_greater_than(10, u16::MAX - 10));
+        assert!(!sequence_greater_than(u16::MAX, 0));
+    }
+
+    #[test]
+    fn ack_bits_slide_with_the_newest_sequence() {
+        let (mut endpoint, wire) = endpoint();
+        assert_eq!(ack_after(&mut endpoint, &wire, 0), (Some(0), 0));
+        assert_eq!(ack_after(&mut endpoint, &wire, 1), (Some(1), 0b1));
+        // 2 is missing
+        assert_eq!(ack_after(&mut endpoint, &wire, 3), (Some(3), 0b110));
+        // and turns up late
+        assert_eq!(ack_after(&mut endpoint, &wire, 2), (Some(3), 0b111));
+        // a repeat changes nothing
+        assert_eq!(ack_after(&mut endpoint, &wire, 1), (Some(3), 0b111));
+        // 0 is the 33rd back and falls out of the window
+        assert_eq!(ack_after(&mut endpoint, &wire, 33), (Some(33), 0b111 << 29));
+        // so does everything after a big gap
+        assert_eq!(ack_after(&mut endpoint, &wire, 100), (Some(100), 0));
+        // and anything older than the window is ignored
+        assert_eq!(ack_after(&mut endpoint, &wire, 67), (Some(100), 0));
+    }
+
+    #[test]
+    fn ack_bits_slide_across_wrap_around() {
+        let (mut endpoint, wire) = endpoint();
+        ack_after(&mut endpoint, &wire, u16::MAX - 1);
+        assert_eq!(ack_after(&mut endpoint, &wire, 1), (Some(1), 0b100));
+        assert_eq!(ack_after(&mut endpoint, &wire, u16::MAX), (Some(1), 0b110));
+        assert_eq!(ack_after(&mut endpoint, &wire, 0), (Some(1), 0b111));
+    }
+
+    #[test]
+    fn acks_sent_packets_across_wrap_around() {
+        let (mut endpoint, wire) = endpoint();
+        endpoint.local_sequence = u16::MAX - 1;
+        let sent: Vec<u16> = (0..4).map(|_| endpoint.flush()).collect();
+        assert_eq!(sent, [u16::MAX - 1, u16::MAX, 0, 1]);
+
+        // the newest and the one three back, but not those in between
+        wire.incoming.borrow_mut().push_back(Packet {
+            ack: Some(1),
+            ack_bits: 0b100,
+            ..Packet::empty(0)
+        });
+        drain(|| endpoint.receive());
+        assert_eq!(endpoint.take_acked(), [1, u16::MAX - 1]);
+        assert_eq!(endpoint.packet_loss(), 0.5);
+    }
+
+    #[test]
+    fn hands_over_reliable_messages_in_order() {
+        let (mut endpoint, wire) = endpoint();
+        let reliable = |ids: &[u16]| Packet {
+            reliable: ids
+                .iter()
+                .map(|&id| (id, Message::Ping { id: id as u32 }))
+                .collect(),
+            ..Packet::empty(0)
+        };
+        let ping_ids = |messages: Vec<Message>| -> Vec<u32> {
+            messages
+                .into_iter()
+                .map(|message| match message {
+                    Message::Ping { id } => id,
+                    other => panic!("unexpected {:?}", other),
+                })
+                .collect()
+        };
+
+        // 1 and 2 arrive before 0, and wait for it
+        wire.incoming.borrow_mut().push_back(Packet {
+            sequence: 1,
+            ..reliable(&[1, 2])
+        });
+        assert!(drain(|| endpoint.receive()).is_empty());
+        wire.incoming.borrow_mut().push_back(Packet {
+            sequence: 0,
+            ..reliable(&[0, 1])
+        });
+        assert_eq!(ping_ids(drain(|| endpoint.receive())), [0, 1, 2]);
+
+        // repeats of what was handed over are dropped
+        wire.incoming.borrow_mut().push_back(Packet {
+            sequence: 2,
+            ..reliable(&[1, 2, 3])
+        });
+        assert_eq!(ping_ids(drain(|| endpoint.receive())), [3]);
+    }
+
+    #[test]
+    fn resends_reliable_messages_until_acked() {
+        let (mut sender, sender_wire) = endpoint();
+        let (mut receiver, receiver_wire) = endpoint();
+        sender.send_reliable(Message::KeepAlive);
+        sender.send_unreliable(Message::Ping { id: 7 });
+
+        // the first packet is lost, with the unreliable message in it
+        sender.flush();
+        let lost = take_sent(&sender_wire);
+        assert_eq!(lost[0].reliable, [(0, Message::KeepAlive)]);
+        assert_eq!(lost[0].unreliable, [Message::Ping { id: 7 }]);
+
+        // the next one repeats only the reliable message, and arrives
+        sender.flush();
+        let sent = take_sent(&sender_wire);
+        assert_eq!(sent[0].reliable, [(0, Message::KeepAlive)]);
+        assert!(sent[0].unreliable.is_empty());
+        receiver_wire.incoming.borrow_mut().extend(sent);
+        assert_eq!(drain(|| receiver.receive()), [Message::KeepAlive]);
+        assert_eq!(sender.pending_reliable(), 1);
+
+        // once the ack is back the message is no longer repeated
+        receiver.flush();
+        sender_wire
+            .incoming
+            .borrow_mut()
+            .extend(take_sent(&receiver_wire));
+        drain(|| sender.receive());
+        assert_eq!(sender.take_acked(), [1]);
+        assert_eq!(sender.pending_reliable(), 0);
+        assert!(!sender.has_queued());
+        assert_eq!(sender.packet_loss(), 0.5);
+        sender.flush();
+        assert!(take_sent(&sender_wire)[0].reliable.is_empty());
+    }
+
+    #[test]
+    fn caps_reliable_messages_per_packet() {
+        let (mut endpoint, wire) = endpoint();
+        for id in 0..MAX_RELIABLE_PER_PACKET as u32 + 4 {
+            endpoint.send_reliable(Message::Ping { id });
+        }
+        endpoint.flush();
+        let sent = take_sent(&wire);
+        assert_eq!(sent[0].reliable.len(), MAX_RELIABLE_PER_PACKET);
+        assert_eq!(sent[0].reliable[0].0, 0);
+    }
+}
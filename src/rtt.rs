@@ -0,0 +1,93 @@
+//! Round trip time measurement.
+//!
+//! Both ends of a connection send a `Ping` every `PING_INTERVAL` and answer the other's pings
+//! with a `Pong` as soon as they arrive. Each pong that comes back gives a sample, which is
+//! smoothed into an estimate the client leads the server's tick by and the server backs its
+//! snapshot rate off with.
+
+use crate::Message;
+use std::collections::VecDeque;
+
+/// Seconds between pings.
+pub const PING_INTERVAL: f64 = 0.25;
+
+/// Pings older than this are given up on as lost.
+const PING_TIMEOUT: f64 = 2.0;
+
+/// Measures the round trip time to the other end of a connection with `Ping`s and `Pong`s,
+/// smoothed the way TCP does it (RFC 6298).
+pub struct RttEstimator {
+    next_ping_id: u32,
+    last_ping_time: Option<f64>,
+    /// Pings still waiting for a pong, with the time they were sent.
+    outstanding: VecDeque<(u32, f64)>,
+    /// Latest round trip sample, in seconds.
+    pub latest: Option<f32>,
+    /// Smoothed round trip time, in seconds. `None` until the first pong arrives.
+    pub smoothed: Option<f32>,
+    /// Smoothed deviation of the samples from `smoothed`, in seconds.
+    pub variance: f32,
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        RttEstimator::new()
+    }
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        RttEstimator {
+            next_ping_id: 0,
+            last_ping_time: None,
+            outstanding: VecDeque::new(),
+            latest: None,
+            smoothed: None,
+            variance: 0.0,
+        }
+    }
+
+    /// Returns a ping to send if `PING_INTERVAL` has passed since the last one.
+    pub fn poll_ping(&mut self, now: f64) -> Option<Message> {
+        if self
+            .last_ping_time
+            .is_some_and(|last| now - last < PING_INTERVAL)
+        {
+            return None;
+        }
+        self.last_ping_time = Some(now);
+
+        self.outstanding
+            .retain(|(_, sent)| now - sent < PING_TIMEOUT);
+        let id = self.next_ping_id;
+        self.next_ping_id = self.next_ping_id.wrapping_add(1);
+        self.outstanding.push_back((id, now));
+
+        Some(Message::Ping { id })
+    }
+
+    /// Takes a sample from the pong answering ping `id`. Duplicated and unknown pongs are ignored.
+    pub fn on_pong(&mut self, id: u32, now: f64) {
+        let Some(index) = self.outstanding.iter().position(|(ping, _)| *ping == id) else {
+            return;
+        };
+        let (_, sent) = self.outstanding.remove(index).unwrap();
+        self.add_sample((now - sent) as f32);
+    }
+
+    /// Takes a round trip of `sample` seconds timed some other way than a ping.
+    pub fn add_sample(&mut self, sample: f32) {
+        self.latest = Some(sample);
+
+        match self.smoothed {
+            None => {
+                self.smoothed = Some(sample);
+                self.variance = sample / 2.0;
+            }
+            Some(smoothed) => {
+                self.variance = 0.75 * self.variance + 0.25 * (smoothed - sample).abs();
+                self.smoothed = Some(0.875 * smoothed + 0.125 * sample);
+            }
+        }
+    }
+}
@@ -1,129 +1,454 @@
-use crate::client::Client;
-use crate::{world_state, Entity, LagNetwork, Message, WorldStateMessage};
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::rc::Rc;
-
-pub(crate) struct Server {
-    pub(crate) clients: Vec<Rc<RefCell<Client>>>,
-    network: LagNetwork,
+use crate::input_buffer::InputBuffer;
+use crate::protocol::{self, Quantization};
+use crate::relevancy::{DistanceFilter, RelevancyFilter};
+use crate::reliability::Endpoint;
+use crate::rtt::RttEstimator;
+use crate::transport::Transport;
+use crate::{world_state, Entity, Message, WorldStateMessage};
+use macroquad::miniquad::date;
+use macroquad::rand::RandGenerator;
+use std::collections::{HashMap, VecDeque};
+
+/// How many sent snapshots are kept per client to serve as delta baselines. Acks for anything
+/// older fall back to a full snapshot.
+pub const SNAPSHOT_HISTORY: usize = 32;
+
+/// Seconds without hearing from the other end before either side gives up on a connection.
+pub const CONNECTION_TIMEOUT: f64 = 5.0;
+
+/// Denied messages sent to a client turned away, as its connection is dropped straight after.
+const DENIED_REDUNDANCY: usize = 3;
+
+/// Loss of packets to a client above which its snapshot rate backs off.
+pub const CONGESTED_LOSS: f32 = 0.1;
+
+/// Round trip time to a client, in seconds, above which its snapshot rate backs off.
+pub const CONGESTED_RTT: f32 = 0.75;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Waiting for the client to echo the salt of the challenge.
+    Challenging {
+        salt: u32,
+    },
+    Connected,
+}
+
+/// The server's end of one client: the endpoint its messages arrive on and the entity it
+/// controls.
+pub struct Connection {
+    pub state: ConnectionState,
+    pub entity_id: u32, // 0 until connected
+    pub endpoint: Endpoint,
+    /// Which entities this client is sent.
+    pub relevancy: Box<dyn RelevancyFilter>,
+    /// Server time the last message from the client arrived.
+    last_heard: f64,
+    pub rtt: RttEstimator,
+    /// Inputs received ahead of their tick.
+    pub input_buffer: InputBuffer,
+    /// Recently sent snapshots with the packet each went out in and its full world state,
+    /// oldest first.
+    sent_snapshots: VecDeque<(u32, u16, Vec<world_state>)>,
+    /// Newest snapshot the client has confirmed receiving.
+    acked_snapshot: Option<u32>,
+    /// Seconds between snapshots to this client, adapted to how well the link copes.
+    pub snapshot_interval: f32,
+    time_since_snapshot: f32,
+    /// Encoded size of the last snapshot sent, for comparing delta and full snapshots.
+    pub snapshot_bytes: usize,
+    /// What the last snapshot would have taken with raw float positions.
+    pub unquantized_snapshot_bytes: usize,
+}
+
+impl Connection {
+    /// Backs the snapshot rate off while the link looks congested, and lets it creep back up
+    /// once it has recovered.
+    fn adapt_snapshot_interval(&mut self, min: f32, max: f32) {
+        let congested = self.endpoint.packet_loss() > CONGESTED_LOSS
+            || self.rtt.smoothed.is_some_and(|rtt| rtt > CONGESTED_RTT);
+        let interval = if congested {
+            self.snapshot_interval * 1.5
+        } else {
+            self.snapshot_interval * 0.95
+        };
+        self.snapshot_interval = interval.clamp(min, max);
+    }
+
+    fn baseline(&self) -> Option<(u32, &[world_state])> {
+        let acked = self.acked_snapshot?;
+        self.sent_snapshots
+            .iter()
+            .find(|(sequence, _, _)| *sequence == acked)
+            .map(|(sequence, _, state)| (*sequence, state.as_slice()))
+    }
+}
+
+pub struct Server {
+    pub connections: Vec<Connection>,
+    next_entity_id: u32,
     time_since_last_update: f32,
-    pub(crate) update_interval: f32, // 20ms for server update interval
-    pub(crate) entities: HashMap<u32, Entity>,
-    pub(crate) last_processed_inputs: HashMap<u32, f32>,
+    pub tick: u32, // number of server updates so far
+    pub time: f64, // seconds passed in update() so far
+    world_state_sequence: u32,
+    /// Length of a simulation tick in seconds. Clients are told on connecting and predict at
+    /// the same rate.
+    pub update_interval: f32,
+    pub entities: HashMap<u32, Entity>,
+    pub last_processed_inputs: HashMap<u32, f32>,
+    /// Fixed-point encoding for snapshot positions, `None` to send raw floats.
+    pub quantization: Option<Quantization>,
+    /// Largest distance an entity was moved by quantizing the last snapshot.
+    pub quantization_error: f32,
+    /// Bounds of the per-client snapshot interval. Snapshots go out on ticks, so anything
+    /// below `update_interval` means every tick.
+    pub min_snapshot_interval: f32,
+    pub max_snapshot_interval: f32,
+    /// Connected clients beyond this many are denied.
+    pub max_clients: usize,
+    rng: RandGenerator,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server::new()
+    }
 }
 
 impl Server {
-    pub(crate) fn new() -> Rc<RefCell<Self>> {
-        Rc::new(RefCell::new(Self {
-            clients: Vec::new(),
-            network: LagNetwork { messages: vec![] },
+    pub fn new() -> Self {
+        Self {
+            connections: Vec::new(),
+            next_entity_id: 1,
             time_since_last_update: 0.0,
-            update_interval: 0.1, // 100 ms
+            tick: 0,
+            time: 0.0,
+            world_state_sequence: 0,
+            update_interval: 0.02, // 20 ms
             entities: HashMap::new(),
             last_processed_inputs: HashMap::new(),
-        }))
+            quantization: None,
+            quantization_error: 0.0,
+            min_snapshot_interval: 0.1,
+            max_snapshot_interval: 0.5,
+            max_clients: 8,
+            rng: {
+                // salts only need to be hard to guess, not reproducible
+                let rng = RandGenerator::new();
+                rng.srand(date::now().to_bits());
+                rng
+            },
+        }
     }
 
-    pub(crate) fn add_client(server: Rc<RefCell<Self>>) -> Rc<RefCell<Client>> {
-        let client = Rc::new(RefCell::new(Client::new(
-            Rc::downgrade(&server), // weak reference to the server
-            0.02,                   // 20 ms
-        )));
+    /// Starts the handshake with a client on `transport`. It gets an entity once it has
+    /// answered the challenge.
+    pub fn connect(&mut self, transport: Box<dyn Transport>) {
+        self.connections.push(Connection {
+            state: ConnectionState::Challenging {
+                salt: self.rng.gen_range(0, u32::MAX),
+            },
+            entity_id: 0,
+            endpoint: Endpoint::new(transport),
+            relevancy: Box::new(DistanceFilter::default()),
+            last_heard: self.time,
+            snapshot_interval: self.min_snapshot_interval,
+            time_since_snapshot: 0.0,
+            rtt: RttEstimator::new(),
+            input_buffer: InputBuffer::new(),
+            sent_snapshots: VecDeque::new(),
+            acked_snapshot: None,
+            snapshot_bytes: 0,
+            unquantized_snapshot_bytes: 0,
+        });
+    }
 
-        server.borrow_mut().clients.push(client.clone());
+    pub fn list_clients(&self) {
+        println!("Server has {} clients.", self.connections.len());
+    }
 
-        // Create an entity for the client
-        let entity_id = server.borrow().clients.len() as u32;
+    /// Handles everything the clients have sent. Run on every update rather than every tick,
+    /// so pings are answered as soon as they arrive instead of waiting for the next tick.
+    fn receiveMessages(&mut self) {
+        let mut connected = self
+            .connections
+            .iter()
+            .filter(|c| c.state == ConnectionState::Connected)
+            .count();
+        // indices of connections to drop once every message is handled
+        let mut departed = Vec::new();
 
-        println!("Creating entity for client: with entity id: {}", entity_id);
+        for (index, connection) in self.connections.iter_mut().enumerate() {
+            while let Some(msg) = connection.endpoint.receive() {
+                connection.last_heard = self.time;
+                let entity_id = connection.entity_id;
 
-        let entity = Entity::new(entity_id);
-        server.borrow_mut().entities.insert(entity_id, entity);
+                match msg {
+                    Message::ConnectRequest => {
+                        // once connected the accept is reliable and is already being repeated
+                        if let ConnectionState::Challenging { salt } = connection.state {
+                            connection
+                                .endpoint
+                                .send_unreliable(Message::Challenge { salt });
+                        }
+                    }
+                    Message::ChallengeResponse { salt } => {
+                        if connection.state != (ConnectionState::Challenging { salt }) {
+                            continue;
+                        }
 
-        client
-    }
+                        if connected >= self.max_clients {
+                            println!("Denying client, the server is full");
+                            for _ in 0..DENIED_REDUNDANCY {
+                                connection.endpoint.send_unreliable(Message::Denied);
+                                connection.endpoint.flush();
+                            }
+                            departed.push(index);
+                            break;
+                        }
 
-    pub(crate) fn list_clients(&self) {
-        println!("Server has {} clients.", self.clients.len());
-    }
+                        // Create an entity for the client
+                        let entity_id = self.next_entity_id;
+                        self.next_entity_id += 1;
+                        connected += 1;
 
-    fn processInputs(&mut self) {
-        while true {
-            if let Some(msg) = self.network.receive() {
-                match msg {
+                        println!("Creating entity for client: with entity id: {}", entity_id);
+
+                        self.entities.insert(entity_id, Entity::new(entity_id));
+                        connection.state = ConnectionState::Connected;
+                        connection.entity_id = entity_id;
+                        // reliable, so it keeps being sent until the client has it
+                        connection.endpoint.send_reliable(Message::Accepted {
+                            entity_id,
+                            tick: self.tick,
+                            tick_interval: self.update_interval,
+                        });
+                    }
+                    Message::Disconnect => {
+                        println!("Entity {} disconnected", entity_id);
+                        departed.push(index);
+                        break;
+                    }
+                    // nothing below is accepted before the handshake is done
+                    _ if connection.state != ConnectionState::Connected => {}
                     Message::Movement(movement_input) => {
-                        // update the entry if it exists
-                        if let Some(entity) = self.entities.get_mut(&movement_input.entity_id) {
-                            self.last_processed_inputs.insert(
-                                movement_input.entity_id,
-                                movement_input.input_sequence_number as f32,
-                            );
-                            entity.applyInput(movement_input);
+                        // a connection may only move its own entity
+                        if movement_input.entity_id != entity_id {
+                            continue;
+                        }
+
+                        // skip duplicated or overtaken inputs, they are already applied
+                        if let Some(&last) = self.last_processed_inputs.get(&entity_id) {
+                            if movement_input.input_sequence_number as f32 <= last {
+                                continue;
+                            }
+                        }
+
+                        // held until the tick it was made for
+                        connection.input_buffer.insert(movement_input, self.tick);
+                    }
+                    Message::Ping { id } => {
+                        // right away, any wait would count towards the client's round trip
+                        connection.endpoint.send_unreliable(Message::Pong { id });
+                        connection.endpoint.flush();
+                    }
+                    Message::Pong { id } => {
+                        connection.rtt.on_pong(id, self.time);
+                    }
+                    Message::KeepAlive => {
+                        // only here to keep the connection from timing out
+                    }
+                    Message::SnapshotNack { sequence } => {
+                        // the client lacks a baseline we built on, start over with a full one
+                        connection
+                            .sent_snapshots
+                            .retain(|(sent, _, _)| *sent > sequence);
+                        if connection
+                            .acked_snapshot
+                            .is_some_and(|acked| acked <= sequence)
+                        {
+                            connection.acked_snapshot = None;
                         }
                     }
-                    Message::WorldState(world_state) => {
+                    Message::WorldState(_)
+                    | Message::Challenge { .. }
+                    | Message::Accepted { .. }
+                    | Message::Denied
+                    | Message::InputBuffer(_) => {
                         // do nothing for now
                     }
                 }
-            } else {
-                break;
             }
         }
+
+        self.remove_connections(departed);
+    }
+
+    fn processInputs(&mut self) {
+        let mut departed = Vec::new();
+
+        for (index, connection) in self.connections.iter_mut().enumerate() {
+            // exactly one input per tick, however they arrived
+            if let Some(input) = connection.input_buffer.take(self.tick) {
+                if let Some(entity) = self.entities.get_mut(&connection.entity_id) {
+                    self.last_processed_inputs
+                        .insert(connection.entity_id, input.input_sequence_number as f32);
+                    entity.applyInput(input, self.update_interval);
+                }
+            }
+
+            if self.time - connection.last_heard > CONNECTION_TIMEOUT {
+                println!("Entity {} timed out", connection.entity_id);
+                departed.push(index);
+            }
+
+            // a snapshot the client has acked can serve as the baseline for the next ones
+            for packet in connection.endpoint.take_acked() {
+                let Some(&(sequence, _, _)) = connection
+                    .sent_snapshots
+                    .iter()
+                    .find(|(_, sent_in, _)| *sent_in == packet)
+                else {
+                    continue;
+                };
+                if connection
+                    .acked_snapshot
+                    .is_none_or(|acked| sequence > acked)
+                {
+                    connection.acked_snapshot = Some(sequence);
+                }
+            }
+
+            // goes out with the next snapshot
+            if connection.state == ConnectionState::Connected {
+                if let Some(ping) = connection.rtt.poll_ping(self.time) {
+                    connection.endpoint.send_unreliable(ping);
+                }
+            }
+        }
+
+        self.remove_connections(departed);
+    }
+
+    /// Drops the connections at `departed`, in ascending order, along with their entities.
+    fn remove_connections(&mut self, departed: Vec<usize>) {
+        // the entity leaves the next snapshots, which is how the other clients learn of it
+        for index in departed.into_iter().rev() {
+            let connection = self.connections.remove(index);
+            self.entities.remove(&connection.entity_id);
+            self.last_processed_inputs.remove(&connection.entity_id);
+        }
     }
 
     fn sendWorldState(&mut self) {
         // println!("Sending world state to clients...");
 
         let mut world_state = Vec::new();
-        for (id, entity) in &self.entities {
+        self.quantization_error = 0.0;
+        for (id, entity) in &mut self.entities {
+            // Move the authoritative entity onto the quantized step too, so clients reconcile
+            // against exactly what the server simulates from. Only the step, the range just
+            // decides how snapshots encode it
+            if let Some(quantization) = &self.quantization {
+                let position = quantization.apply(entity.x);
+                self.quantization_error = self.quantization_error.max((entity.x - position).abs());
+                entity.x = position;
+            }
+
             world_state.push(world_state {
                 entity_id: *id,
                 position: entity.x,
-                last_processed_input: self.last_processed_inputs.get(id).unwrap_or(&0.0).clone(),
+                last_processed_input: *self.last_processed_inputs.get(id).unwrap_or(&0.0),
             });
         }
 
-        let world_state_message = WorldStateMessage { world_state };
+        self.world_state_sequence += 1;
+        let sequence = self.world_state_sequence;
+        let time_ms = (self.time * 1000.0) as u32;
 
-        // Send the world state to all clients
-        for client in &self.clients {
-            let mut client = client.borrow_mut();
-            let latency = client.latency_to_server;
-            client
-                .network
-                .send(latency, Message::WorldState(world_state_message.clone()));
-        }
-    }
+        // Send the world state to all clients, as a delta against what each last acknowledged
+        for connection in &mut self.connections {
+            if connection.state != ConnectionState::Connected {
+                // only the handshake replies go out
+                connection.endpoint.flush();
+                continue;
+            }
 
-    pub(crate) fn update(&mut self, delta_time: f32) {
-        // tell clients to update
-        self.time_since_last_update += delta_time;
+            // each client gets its snapshots on the tick nearest to its own interval
+            connection.time_since_snapshot += self.update_interval;
+            if connection.time_since_snapshot
+                < connection.snapshot_interval - self.update_interval / 2.0
+            {
+                if connection.endpoint.has_queued() {
+                    connection.endpoint.flush();
+                }
+                continue;
+            }
+            connection.time_since_snapshot = 0.0;
+            connection
+                .adapt_snapshot_interval(self.min_snapshot_interval, self.max_snapshot_interval);
 
-        let server_update_interval = self.update_interval;
+            // entities that stop being relevant show up as removed in the delta
+            let viewer = self.entities.get(&connection.entity_id);
+            let relevant: Vec<world_state> = world_state
+                .iter()
+                .filter(|state| {
+                    state.entity_id == connection.entity_id
+                        || viewer.is_none_or(|viewer| {
+                            connection
+                                .relevancy
+                                .is_relevant(viewer, &self.entities[&state.entity_id])
+                        })
+                })
+                .cloned()
+                .collect();
 
-        for client in &self.clients {
-            let mut messages = vec![];
-            if let Some(msg) = client
-                .borrow_mut()
-                .update(delta_time, server_update_interval)
-            {
-                messages.push(msg);
+            let mut snapshot = WorldStateMessage::delta(
+                sequence,
+                self.tick,
+                time_ms,
+                &relevant,
+                connection.baseline(),
+                None,
+            );
+            connection.unquantized_snapshot_bytes =
+                protocol::encoded_size(&Message::WorldState(snapshot.clone()));
+
+            if let Some(quantization) = &self.quantization {
+                snapshot.position_bits = Some(quantization.precision_bits);
+                snapshot.float_positions = !snapshot
+                    .world_state
+                    .iter()
+                    .all(|state| quantization.contains(state.position));
             }
-            for msg in messages {
-                let client_latency = client.borrow().latency_to_server;
-                self.network.send(client_latency, msg); // Process outside of client loop
+            let message = Message::WorldState(snapshot);
+            connection.snapshot_bytes = protocol::encoded_size(&message);
+            connection.endpoint.send_unreliable(message);
+            connection
+                .endpoint
+                .send_unreliable(Message::InputBuffer(connection.input_buffer.status()));
+            let packet = connection.endpoint.flush();
+
+            connection
+                .sent_snapshots
+                .push_back((sequence, packet, relevant));
+            if connection.sent_snapshots.len() > SNAPSHOT_HISTORY {
+                connection.sent_snapshots.pop_front();
             }
         }
+    }
 
+    pub fn update(&mut self, delta_time: f32) {
         // do server updates
         self.time_since_last_update += delta_time;
+        self.time += delta_time as f64;
+        self.receiveMessages();
 
-        if self.time_since_last_update >= self.update_interval {
+        // fixed ticks, catching up if more than one has passed
+        while self.time_since_last_update >= self.update_interval {
             self.time_since_last_update -= self.update_interval; // Reset time
-                                                                 // println!("Server updated!");
-                                                                 // println!("Server updated!");
-                                                                 // Process inputs and send world state
+            self.tick += 1;
+            // Process inputs and send world state
             self.processInputs();
             self.sendWorldState();
         }
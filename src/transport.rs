@@ -0,0 +1,10 @@
+use crate::Packet;
+
+/// One end of a connection between a client and the server.
+pub trait Transport {
+    /// Sends `packet` to the other end. Delivery is not guaranteed.
+    fn send(&mut self, packet: Packet);
+
+    /// Returns the next packet that has arrived from the other end, if any.
+    fn receive(&mut self) -> Option<Packet>;
+}
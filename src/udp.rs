@@ -0,0 +1,133 @@
+use crate::protocol::{decode, encode};
+use crate::transport::Transport;
+use crate::Packet;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::rc::Rc;
+
+/// Large enough for any datagram on a typical Ethernet path.
+const MAX_DATAGRAM_SIZE: usize = 1500;
+
+/// Client end of a UDP connection to a server.
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    /// Binds an ephemeral local port and sends everything to `server`.
+    pub fn connect(server: impl ToSocketAddrs) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(server)?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpTransport { socket })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, packet: Packet) {
+        // a failed send is just another lost datagram
+        let _ = self.socket.send(&encode(&packet));
+    }
+
+    fn receive(&mut self) -> Option<Packet> {
+        let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            match self.socket.recv(&mut buffer) {
+                Ok(len) => match decode(&buffer[..len]) {
+                    Ok(packet) => return Some(packet),
+                    Err(e) => eprintln!("Dropping packet from server: {}", e),
+                },
+                // WouldBlock when nothing is waiting, or e.g. a refused connection while the
+                // server is not up yet
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+/// Server side UDP socket shared by every client, handing out one `UdpPeer` per address.
+pub struct UdpListener {
+    socket: Rc<UdpSocket>,
+    peers: HashMap<SocketAddr, Rc<RefCell<VecDeque<Packet>>>>,
+}
+
+impl UdpListener {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpListener {
+            socket: Rc::new(socket),
+            peers: HashMap::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Reads every waiting datagram into the inbox of the peer that sent it. Returns a
+    /// transport for each address heard from for the first time, or for the first time since
+    /// its previous `UdpPeer` was dropped.
+    pub fn poll(&mut self) -> Vec<UdpPeer> {
+        // forget addresses whose peer is gone, so they can connect again
+        self.peers.retain(|_, inbox| Rc::strong_count(inbox) > 1);
+
+        let mut new_peers = Vec::new();
+        let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                // some platforms report a client that went away on the next read
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(_) => break,
+            };
+            let packet = match decode(&buffer[..len]) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    eprintln!("Dropping packet from {}: {}", addr, e);
+                    continue;
+                }
+            };
+
+            let inbox = self.peers.entry(addr).or_insert_with(|| {
+                let inbox = Rc::new(RefCell::new(VecDeque::new()));
+                new_peers.push(UdpPeer {
+                    socket: self.socket.clone(),
+                    addr,
+                    inbox: inbox.clone(),
+                });
+                inbox
+            });
+            inbox.borrow_mut().push_back(packet);
+        }
+
+        new_peers
+    }
+}
+
+/// Server end of the connection with one client, sharing the listener's socket.
+pub struct UdpPeer {
+    socket: Rc<UdpSocket>,
+    addr: SocketAddr,
+    inbox: Rc<RefCell<VecDeque<Packet>>>,
+}
+
+impl UdpPeer {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Transport for UdpPeer {
+    fn send(&mut self, packet: Packet) {
+        // a failed send is just another lost datagram
+        let _ = self.socket.send_to(&encode(&packet), self.addr);
+    }
+
+    fn receive(&mut self) -> Option<Packet> {
+        self.inbox.borrow_mut().pop_front()
+    }
+}